@@ -0,0 +1,285 @@
+use std::{collections::HashMap, fmt, path::Path, str::FromStr};
+
+use anyhow::{anyhow, bail, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use digest::DynDigest;
+use reqwest::header::{HeaderMap, ETAG};
+use tokio::{fs, io::AsyncReadExt};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HashAlgorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    pub(crate) fn hasher(self) -> Box<dyn DynDigest + Send> {
+        match self {
+            HashAlgorithm::Md5 => Box::new(md5::Md5::default()),
+            HashAlgorithm::Sha1 => Box::new(sha1::Sha1::default()),
+            HashAlgorithm::Sha256 => Box::new(sha2::Sha256::default()),
+            HashAlgorithm::Sha512 => Box::new(sha2::Sha512::default()),
+        }
+    }
+
+    /// Algorithm whose hex digest has `len` characters
+    fn from_hex_len(len: usize) -> Option<Self> {
+        match len {
+            32 => Some(HashAlgorithm::Md5),
+            40 => Some(HashAlgorithm::Sha1),
+            64 => Some(HashAlgorithm::Sha256),
+            128 => Some(HashAlgorithm::Sha512),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().replace('-', "").as_str() {
+            "md5" => Ok(HashAlgorithm::Md5),
+            "sha1" => Ok(HashAlgorithm::Sha1),
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "sha512" => Ok(HashAlgorithm::Sha512),
+            _ => bail!("Unsupported hash algorithm {}", s),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            HashAlgorithm::Md5 => "md5",
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha512 => "sha512",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Expected digest of a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksum {
+    pub algorithm: HashAlgorithm,
+    pub digest: Vec<u8>,
+}
+
+impl Checksum {
+    pub fn from_hex(algorithm: HashAlgorithm, digest: &str) -> Result<Self> {
+        let digest = hex::decode(digest.trim())?;
+        let size = algorithm.hasher().output_size();
+        if digest.len() != size {
+            bail!(
+                "{} digest must be {} bytes, got {}",
+                algorithm,
+                size,
+                digest.len()
+            );
+        }
+        Ok(Self { algorithm, digest })
+    }
+}
+
+impl FromStr for Checksum {
+    type Err = anyhow::Error;
+
+    /// `sha256=abcd...`, also accepting `:` as separator
+    fn from_str(s: &str) -> Result<Self> {
+        let (algorithm, digest) = s
+            .split_once(['=', ':'])
+            .ok_or_else(|| anyhow!("Expected algorithm=digest, got {}", s))?;
+        Checksum::from_hex(algorithm.parse()?, digest)
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.algorithm, hex::encode(&self.digest))
+    }
+}
+
+/// Expected digests of consecutive fixed size pieces of a file, as listed by Metalink
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PieceHashes {
+    /// Size of every piece but the last
+    pub length: u64,
+    pub algorithm: HashAlgorithm,
+    pub digests: Vec<Vec<u8>>,
+}
+
+/// Downloaded content did not match its expected digest
+#[derive(Debug)]
+pub struct ChecksumMismatch {
+    pub expected: Checksum,
+    pub actual: Vec<u8>,
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Checksum mismatch, expected {} but got {}",
+            self.expected,
+            hex::encode(&self.actual)
+        )
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+/// Parse a `SHA256SUMS` style file, `<hex digest>  <file name>` per line, keyed by file name.
+///
+/// The algorithm is chosen from the digest length, so md5, sha1, sha256 and sha512 sums all work.
+pub fn parse_checksum_file(content: &str) -> HashMap<String, Checksum> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let (digest, name) = line.trim().split_once(char::is_whitespace)?;
+            let algorithm = HashAlgorithm::from_hex_len(digest.len())?;
+            let checksum = Checksum::from_hex(algorithm, digest).ok()?;
+            // A leading `*` marks binary mode in the coreutils format
+            let name = name.trim_start().trim_start_matches('*');
+            let name = name.rsplit('/').next().unwrap_or(name);
+            Some((name.to_string(), checksum))
+        })
+        .collect()
+}
+
+/// Strongest digest the server sent for the whole body, from `Repr-Digest`, `Digest`,
+/// `x-goog-hash` or `Content-MD5`, or else an ETag that looks like an MD5.
+///
+/// On ties the header listed first wins.
+pub(crate) fn get_server_checksum(headers: &HeaderMap) -> Option<(&'static str, Checksum)> {
+    let values = |name: &str| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .filter_map(|item| item.split_once('='))
+            .map(|(algorithm, digest)| (algorithm.trim().to_ascii_lowercase(), digest.trim()))
+            .collect::<Vec<_>>()
+    };
+    let from_base64 = |algorithm: HashAlgorithm, digest: &str| {
+        let digest = STANDARD.decode(digest.trim_matches(':')).ok()?;
+        (digest.len() == algorithm.hasher().output_size()).then_some(Checksum { algorithm, digest })
+    };
+    let mut found: Vec<(&'static str, Checksum)> = Vec::new();
+    for (algorithm, digest) in values("repr-digest") {
+        let algorithm = match algorithm.as_str() {
+            "sha-256" => HashAlgorithm::Sha256,
+            "sha-512" => HashAlgorithm::Sha512,
+            _ => continue,
+        };
+        found.extend(from_base64(algorithm, digest).map(|c| ("Repr-Digest", c)));
+    }
+    for (algorithm, digest) in values("digest") {
+        let algorithm = match algorithm.as_str() {
+            "sha-256" => HashAlgorithm::Sha256,
+            "sha-512" => HashAlgorithm::Sha512,
+            "sha" => HashAlgorithm::Sha1,
+            "md5" => HashAlgorithm::Md5,
+            _ => continue,
+        };
+        found.extend(from_base64(algorithm, digest).map(|c| ("Digest", c)));
+    }
+    for (algorithm, digest) in values("x-goog-hash") {
+        if algorithm == "md5" {
+            found.extend(from_base64(HashAlgorithm::Md5, digest).map(|c| ("x-goog-hash", c)));
+        }
+    }
+    if let Some(digest) = headers.get("content-md5").and_then(|v| v.to_str().ok()) {
+        found.extend(from_base64(HashAlgorithm::Md5, digest).map(|c| ("Content-MD5", c)));
+    }
+    if let Some(checksum) = found
+        .iter()
+        .rev()
+        .max_by_key(|(_, c)| strength(c.algorithm))
+    {
+        return Some(checksum.clone());
+    }
+
+    // Multipart uploads and most web servers use ETags that are not a digest of the body
+    let etag = headers.get(ETAG)?.to_str().ok()?;
+    let etag = etag.strip_prefix('"')?.strip_suffix('"')?;
+    if etag.len() == 32 && etag.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Some(("ETag", Checksum::from_hex(HashAlgorithm::Md5, etag).ok()?));
+    }
+    None
+}
+
+pub(crate) fn strength(algorithm: HashAlgorithm) -> u8 {
+    match algorithm {
+        HashAlgorithm::Md5 => 0,
+        HashAlgorithm::Sha1 => 1,
+        HashAlgorithm::Sha256 => 2,
+        HashAlgorithm::Sha512 => 3,
+    }
+}
+
+/// Digests of a file that is already on disk, one per algorithm, reading it once
+pub(crate) async fn hash_file(path: &Path, algorithms: &[HashAlgorithm]) -> Result<Vec<Vec<u8>>> {
+    let mut hashers: Vec<_> = algorithms.iter().map(|a| a.hasher()).collect();
+    update_from_file(&mut hashers, path).await?;
+    Ok(hashers
+        .into_iter()
+        .map(|hasher| hasher.finalize().into_vec())
+        .collect())
+}
+
+/// Feed the content of a file to every hasher
+pub(crate) async fn update_from_file(
+    hashers: &mut [Box<dyn DynDigest + Send>],
+    path: &Path,
+) -> Result<()> {
+    let mut file = fs::File::open(path).await?;
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        for hasher in hashers.iter_mut() {
+            hasher.update(&buffer[..n]);
+        }
+    }
+    Ok(())
+}
+
+/// Check each piece of a file that is already on disk, failing on the first one that differs
+pub(crate) async fn verify_pieces(path: &Path, pieces: &PieceHashes) -> Result<()> {
+    let mut file = fs::File::open(path).await?;
+    // The piece length comes from the list, so read it in chunks rather than at once
+    let mut buffer = vec![0; 64 * 1024];
+    for (n, expected) in pieces.digests.iter().enumerate() {
+        let mut hasher = pieces.algorithm.hasher();
+        let mut remaining = pieces.length;
+        while remaining > 0 {
+            let len = buffer.len().min(remaining.try_into().unwrap_or(usize::MAX));
+            match file.read(&mut buffer[..len]).await? {
+                0 => break,
+                read => {
+                    hasher.update(&buffer[..read]);
+                    remaining -= read as u64;
+                }
+            }
+        }
+        let actual = hasher.finalize().into_vec();
+        if actual != *expected {
+            return Err(anyhow::Error::new(ChecksumMismatch {
+                expected: Checksum {
+                    algorithm: pieces.algorithm,
+                    digest: expected.clone(),
+                },
+                actual,
+            })
+            .context(format!("Piece {} does not match", n)));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,135 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs,
+    sync::{Mutex, OwnedMutexGuard},
+};
+
+use crate::naming::fit_file_name;
+
+/// What to do when a file name is already taken, on disk or by another url of the run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    Overwrite,
+    /// Keep the existing file and do not download
+    Skip,
+    /// Add a counter, `image (1).jpg`
+    #[default]
+    Rename,
+    /// Fail the download
+    Error,
+    /// Add a hash of the url, `image-1a2b3c4d.jpg`
+    HashSuffix,
+}
+
+/// The file name chosen for a download is already taken
+#[derive(Debug)]
+pub struct Conflict(pub PathBuf);
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} already exists", self.0.display())
+    }
+}
+
+impl std::error::Error for Conflict {}
+
+/// Paths handed out during one run, keyed to the job that owns them
+#[derive(Debug, Default)]
+pub(crate) struct Claims {
+    paths: Mutex<HashMap<PathBuf, usize>>,
+    /// Overwriting jobs share a path, and with it the partial file, so take turns writing it
+    writers: Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>,
+}
+
+impl Claims {
+    /// Final path for job `index` that wants `path`, or `None` when it should be skipped
+    pub(crate) async fn claim(
+        &self,
+        policy: ConflictPolicy,
+        path: PathBuf,
+        index: usize,
+        url: &Url,
+    ) -> Result<Option<PathBuf>> {
+        let mut paths = self.paths.lock().await;
+        // A retry of the same job keeps the path it was given before
+        if let Some(owner) = paths.get(&path) {
+            if *owner == index {
+                return Ok(Some(path));
+            }
+        }
+        if !is_taken(&paths, &path, index).await {
+            paths.insert(path.clone(), index);
+            return Ok(Some(path));
+        }
+
+        let base = match policy {
+            ConflictPolicy::Overwrite => {
+                paths.insert(path.clone(), index);
+                return Ok(Some(path));
+            }
+            ConflictPolicy::Skip => return Ok(None),
+            ConflictPolicy::Error => return Err(Conflict(path).into()),
+            ConflictPolicy::Rename => path.clone(),
+            ConflictPolicy::HashSuffix => {
+                let hash = format!("{:x}", Sha256::digest(url.as_str()));
+                with_stem_suffix(&path, &format!("-{}", &hash[..8]))
+            }
+        };
+        let mut candidate = base.clone();
+        let mut counter = 1;
+        while is_taken(&paths, &candidate, index).await {
+            candidate = with_stem_suffix(&base, &format!(" ({})", counter));
+            counter += 1;
+        }
+        paths.insert(candidate.clone(), index);
+        Ok(Some(candidate))
+    }
+}
+
+impl Claims {
+    /// Whether a job other than `index` took `path` during this run
+    pub(crate) async fn is_claimed(&self, path: &Path, index: usize) -> bool {
+        let paths = self.paths.lock().await;
+        paths.get(path).is_some_and(|owner| *owner != index)
+    }
+
+    /// Wait until no other job is writing `path`, and keep others out while the guard lives
+    pub(crate) async fn lock_for_writing(&self, path: &Path) -> OwnedMutexGuard<()> {
+        let lock = self
+            .writers
+            .lock()
+            .await
+            .entry(path.to_path_buf())
+            .or_default()
+            .clone();
+        lock.lock_owned().await
+    }
+}
+
+async fn is_taken(paths: &HashMap<PathBuf, usize>, path: &Path, index: usize) -> bool {
+    paths.get(path).is_some_and(|owner| *owner != index)
+        || fs::try_exists(path).await.unwrap_or(true)
+}
+
+/// `dir/image.jpg` + `-x` gives `dir/image-x.jpg`
+fn with_stem_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let tail = match path.extension() {
+        Some(ext) => format!("{}.{}", suffix, ext.to_string_lossy()),
+        None => suffix.to_string(),
+    };
+    let name = fit_file_name(&stem, &tail);
+    path.with_file_name(name)
+}
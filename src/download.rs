@@ -0,0 +1,688 @@
+use std::{
+    fmt,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{bail, Result};
+use backon::Retryable;
+use lazy_regex::regex_captures;
+use reqwest::{
+    header::{
+        HeaderMap, ACCEPT_RANGES, CONTENT_DISPOSITION, CONTENT_RANGE, CONTENT_TYPE, ETAG,
+        IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, RANGE,
+    },
+    Client, RequestBuilder, StatusCode, Url,
+};
+use tokio::{
+    fs,
+    io::{AsyncSeekExt, AsyncWriteExt},
+    task::JoinSet,
+};
+use tracing::{info, instrument};
+
+use crate::{
+    checksum::{
+        get_server_checksum, hash_file, update_from_file, verify_pieces, Checksum,
+        ChecksumMismatch, HashAlgorithm,
+    },
+    conflict::{Claims, Conflict, ConflictPolicy},
+    downloader::{Config, Download, Job, Session, Status},
+    mime::{extension_from_mime, fix_extension, sniff_extension, ExtensionPolicy},
+    naming::{get_file_name_from_url, parse_content_disposition, sanitize_file_name, NameContext},
+    progress::{Event, Reporter},
+};
+
+/// Files smaller than this are never split, and no segment is made smaller than this
+const MIN_SEGMENT_SIZE: u64 = 1024 * 1024;
+
+/// Errors that another attempt cannot fix
+pub(crate) fn is_retryable(e: &anyhow::Error) -> bool {
+    !e.is::<Conflict>()
+}
+
+/// The body ended before all the bytes the server announced had arrived
+#[derive(Debug)]
+pub struct BodyTruncated {
+    pub expected: u64,
+    pub actual: u64,
+}
+
+impl fmt::Display for BodyTruncated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Body truncated, got {} of {} bytes",
+            self.actual, self.expected
+        )
+    }
+}
+
+impl std::error::Error for BodyTruncated {}
+
+/// The file does not have the size given in the url list
+#[derive(Debug)]
+pub struct SizeMismatch {
+    pub expected: u64,
+    pub actual: u64,
+}
+
+impl fmt::Display for SizeMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Expected {} bytes, got {}", self.expected, self.actual)
+    }
+}
+
+impl std::error::Error for SizeMismatch {}
+
+/// Download one job, starting with the url at `next_url` among the url and its mirrors.
+///
+/// `next_url` is moved past the url that answers, so a retry after a broken body or a
+/// digest mismatch goes to the next mirror.
+#[instrument(skip_all, fields(url = %job.url))]
+pub(crate) async fn download_image(
+    session: Arc<Session>,
+    job: Job,
+    index: usize,
+    next_url: &AtomicUsize,
+) -> Result<Download> {
+    let Session {
+        client,
+        config,
+        claims,
+        reporter,
+        journal,
+    } = session.as_ref();
+    info!("Process url {}", job.url.to_string());
+    // Headers of the job replace downloader headers of the same name
+    let mut request_headers = config.headers.clone();
+    request_headers.extend(job.headers.clone());
+    let request_to = |url: &Url| client.get(url.clone()).headers(request_headers.clone());
+
+    // Ask the server whether the file of an earlier run is still current
+    let previous = journal
+        .as_ref()
+        .filter(|_| config.update)
+        .and_then(|journal| journal.finished(&job))
+        .filter(|previous| previous.downloaded());
+
+    // Without a request the name can only be guessed from the url, the real one is checked later.
+    // Files of an earlier run are checked by an update rather than skipped.
+    if config.skip_existing && previous.is_none() {
+        let relative = config.naming.resolve(&NameContext {
+            index,
+            job: &job,
+            suggested: get_file_name_from_url(&job.url).as_deref(),
+            extension: None,
+        });
+        let path = config.output.join(relative);
+        if existed_before(claims, &path, index).await? {
+            return Ok(skipped(path));
+        }
+    }
+
+    // Fall back to the mirrors in order, later requests go to the url that answered
+    let urls: Vec<_> = std::iter::once(&job.url).chain(&job.mirrors).collect();
+    let first = next_url.load(Ordering::Relaxed) % urls.len();
+    let mut failure = None;
+    let mut answer = None;
+    for (n, url) in urls.iter().enumerate().cycle().skip(first).take(urls.len()) {
+        let mut request = request_to(url);
+        if let Some(previous) = previous {
+            if let Some(etag) = &previous.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &previous.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+        match request.send().await.and_then(|r| r.error_for_status()) {
+            Ok(response) => {
+                next_url.store(n + 1, Ordering::Relaxed);
+                answer = Some((n, (*url).clone(), response));
+                break;
+            }
+            Err(e) if !job.mirrors.is_empty() => {
+                info!("Try the next mirror, {}", e);
+                failure = Some(e);
+            }
+            Err(e) => failure = Some(e),
+        }
+    }
+    let Some((answered, url, mut response)) = answer else {
+        return Err(failure.expect("a job has at least one url").into());
+    };
+    let request_builder = || request_to(&url);
+    let headers = response.headers().clone();
+    if let (Some(previous), StatusCode::NOT_MODIFIED) = (previous, response.status()) {
+        let (etag, last_modified) = get_validators(&headers);
+        return Ok(Download {
+            path: previous.path.clone(),
+            status: Status::Unchanged,
+            etag: etag.or_else(|| previous.etag.clone()),
+            last_modified: last_modified.or_else(|| previous.last_modified.clone()),
+            http_status: Some(StatusCode::NOT_MODIFIED),
+            sha256: None,
+        });
+    }
+    let file_name = headers
+        .get(CONTENT_DISPOSITION)
+        .and_then(|h| parse_content_disposition(h.as_bytes()))
+        .and_then(|name| sanitize_file_name(&name))
+        .or_else(|| get_file_name_from_url(&job.url));
+
+    // Read before peeking, as the body size hint shrinks once a chunk is taken
+    let mut content_length = response.content_length();
+
+    // Peek at the body only when the Content-Type does not tell what the file is
+    let mut first_chunk = None;
+    let extension = match headers
+        .get(CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .and_then(extension_from_mime)
+    {
+        Some(extension) => Some(extension),
+        None if config.extensions != ExtensionPolicy::Keep => {
+            first_chunk = response.chunk().await?;
+            first_chunk.as_deref().and_then(sniff_extension)
+        }
+        None => None,
+    };
+    let relative = config.naming.resolve(&NameContext {
+        index,
+        job: &job,
+        suggested: file_name.as_deref(),
+        extension,
+    });
+    // A name given for the job is used as is
+    let extensions = match job.name {
+        Some(_) => ExtensionPolicy::Keep,
+        None => config.extensions,
+    };
+    let path = config
+        .output
+        .join(fix_extension(relative, extension, extensions));
+    if config.skip_existing && previous.is_none() && existed_before(claims, &path, index).await? {
+        return Ok(skipped(path));
+    }
+    // A newer version replaces the file of the earlier run, whatever its name would be now
+    let (path, policy) = match previous {
+        Some(previous) => (previous.path.clone(), ConflictPolicy::Overwrite),
+        None => (path, config.on_conflict),
+    };
+    let Some(path) = claims.claim(policy, path.clone(), index, &job.url).await? else {
+        return Ok(skipped(path));
+    };
+    let _writing = claims.lock_for_writing(&path).await;
+    let (etag, last_modified) = get_validators(&headers);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+
+    let part_path = with_suffix(&path, ".part");
+    let meta_path = with_suffix(&path, ".part.meta");
+    remove_stale_part(&part_path, &meta_path).await?;
+
+    // Continue a previous partial download if the server still has the same content
+    let mut offset = 0;
+    if let (Ok(part), Ok(validator)) = (
+        fs::metadata(&part_path).await,
+        fs::read_to_string(&meta_path).await,
+    ) {
+        if part.len() > 0 && !validator.trim().is_empty() {
+            let resumed =
+                send_range_request(request_builder(), part.len(), validator.trim()).await?;
+            if resumed.status() == StatusCode::PARTIAL_CONTENT {
+                if get_range_start(resumed.headers()) == Some(part.len()) {
+                    info!("Resume {} from byte {}", path.display(), part.len());
+                    offset = part.len();
+                    content_length = resumed.content_length();
+                    response = resumed;
+                    first_chunk = None;
+                }
+            } else if resumed.status().is_success() {
+                // The server ignored the range, so this is a full body
+                content_length = resumed.content_length();
+                response = resumed;
+                first_chunk = None;
+            }
+        }
+    }
+
+    // A body of the wrong size is not worth downloading
+    if let (Some(size), Some(len)) = (job.size, content_length) {
+        if len + offset != size {
+            return Err(anyhow::Error::new(SizeMismatch {
+                expected: size,
+                actual: len + offset,
+            })
+            .context("The server has a different size"));
+        }
+    }
+
+    // Digests the content is checked against, with where they came from
+    let mut expected = Vec::new();
+    if let Some(checksum) = &job.checksum {
+        expected.push(("the url list".to_string(), checksum.clone()));
+    }
+    if config.verify_server_digest {
+        if let Some((header, checksum)) = get_server_checksum(&headers) {
+            expected.push((format!("the {} header", header), checksum));
+        }
+    }
+
+    let http_status = Some(response.status());
+
+    // Split large files into byte ranges fetched over separate connections
+    let accept_ranges = response
+        .headers()
+        .get(ACCEPT_RANGES)
+        .is_some_and(|v| v == "bytes");
+    if let Some(total) = content_length {
+        if offset == 0 && config.segments > 1 && accept_ranges && total >= 2 * MIN_SEGMENT_SIZE {
+            let validator = get_validator(response.headers());
+            drop(response);
+            // A preallocated file cannot be resumed by length, so forget any validator
+            fs::write(&meta_path, "").await?;
+            reporter.send(Event::Started {
+                index,
+                name: get_display_name(&path),
+                size: Some(total),
+                position: 0,
+            });
+            // Segments that fail go on with the next mirror, starting from the url that answered
+            let segment_urls: Vec<_> = urls
+                .iter()
+                .cycle()
+                .skip(answered)
+                .take(urls.len())
+                .map(|url| (*url).clone())
+                .collect();
+            download_segments(
+                config,
+                client,
+                &segment_urls,
+                &request_headers,
+                validator,
+                &part_path,
+                total,
+                index,
+                reporter,
+            )
+            .await?;
+            verify_size_and_pieces(&job, &part_path).await?;
+            // Segments arrive out of order, so hash the file once it is whole
+            let algorithms: Vec<_> = expected
+                .iter()
+                .map(|(_, c)| c.algorithm)
+                .chain([HashAlgorithm::Sha256])
+                .collect();
+            let mut digests = hash_file(&part_path, &algorithms).await?;
+            let sha256 = digests.pop();
+            for ((source, expected), actual) in expected.iter().zip(digests) {
+                verify_checksum(source, expected, actual, &part_path).await?;
+            }
+            move_into_place(&part_path, &path, config.fsync).await?;
+            remove_if_exists(&meta_path).await?;
+            return Ok(Download {
+                path,
+                status: Status::Downloaded,
+                etag,
+                last_modified,
+                http_status,
+                sha256,
+            });
+        }
+    }
+
+    // Hash while streaming, starting with whatever an earlier attempt left on disk. The
+    // SHA-256 goes to the journal.
+    let mut hashers: Vec<_> = expected
+        .iter()
+        .map(|(_, c)| c.algorithm)
+        .chain([HashAlgorithm::Sha256])
+        .map(HashAlgorithm::hasher)
+        .collect();
+    if offset > 0 {
+        update_from_file(&mut hashers, &part_path).await?;
+    }
+
+    // Write each chunk as it arrives so memory use does not grow with file size
+    let mut file = if offset > 0 {
+        fs::OpenOptions::new().append(true).open(&part_path).await?
+    } else {
+        let validator = get_validator(response.headers()).unwrap_or_default();
+        fs::write(&meta_path, validator).await?;
+        fs::File::create(&part_path).await?
+    };
+    reporter.send(Event::Started {
+        index,
+        name: get_display_name(&path),
+        size: content_length.map(|len| len + offset),
+        position: offset,
+    });
+    let mut received = 0;
+    while let Some(chunk) = match first_chunk.take() {
+        Some(chunk) => Some(chunk),
+        None => response.chunk().await?,
+    } {
+        file.write_all(&chunk).await?;
+        received += chunk.len() as u64;
+        for hasher in hashers.iter_mut() {
+            hasher.update(&chunk);
+        }
+        reporter.send(Event::Received {
+            index,
+            bytes: chunk.len() as u64,
+        });
+    }
+    file.flush().await?;
+    drop(file);
+
+    // Keep the partial file so the retry continues where this attempt stopped
+    if let Some(len) = content_length.filter(|len| *len != received) {
+        return Err(BodyTruncated {
+            expected: len,
+            actual: received,
+        }
+        .into());
+    }
+    let verified = job.size.is_some() || job.pieces.is_some();
+    if verified || !expected.is_empty() {
+        fs::write(&meta_path, "").await?;
+    }
+    verify_size_and_pieces(&job, &part_path).await?;
+    let mut digests: Vec<_> = hashers
+        .into_iter()
+        .map(|h| h.finalize().into_vec())
+        .collect();
+    let sha256 = digests.pop();
+    for ((source, expected), actual) in expected.iter().zip(digests) {
+        verify_checksum(source, expected, actual, &part_path).await?;
+    }
+
+    move_into_place(&part_path, &path, config.fsync).await?;
+    remove_if_exists(&meta_path).await?;
+    Ok(Download {
+        path,
+        status: Status::Downloaded,
+        etag,
+        last_modified,
+        http_status,
+        sha256,
+    })
+}
+
+/// Rename a finished partial file to its final name, so a file under that name is always complete.
+///
+/// With `sync`, the content is flushed to disk before the rename and the directory after it.
+async fn move_into_place(part_path: &Path, path: &Path, sync: bool) -> Result<()> {
+    if sync {
+        fs::File::open(part_path).await?.sync_all().await?;
+    }
+    fs::rename(part_path, path).await?;
+    // Directories cannot be opened as files on Windows
+    #[cfg(unix)]
+    if sync {
+        if let Some(parent) = path.parent() {
+            fs::File::open(parent).await?.sync_all().await?;
+        }
+    }
+    Ok(())
+}
+
+/// Delete a partial file left by an earlier run that cannot be resumed, because its
+/// validator is empty or the `.part` file is gone
+async fn remove_stale_part(part_path: &Path, meta_path: &Path) -> Result<()> {
+    let Ok(validator) = fs::read_to_string(meta_path).await else {
+        return Ok(());
+    };
+    if validator.trim().is_empty() || !fs::try_exists(part_path).await? {
+        info!("Remove leftover {}", part_path.display());
+        remove_partial(part_path).await?;
+    }
+    Ok(())
+}
+
+/// Delete a partial file and its `.part.meta`
+async fn remove_partial(part_path: &Path) -> Result<()> {
+    remove_if_exists(part_path).await?;
+    remove_if_exists(&with_suffix(part_path, ".meta")).await
+}
+
+/// Delete the partial file when its digest is wrong, so the retry starts over
+async fn verify_checksum(
+    source: &str,
+    expected: &Checksum,
+    actual: Vec<u8>,
+    part_path: &Path,
+) -> Result<()> {
+    if actual == expected.digest {
+        return Ok(());
+    }
+    remove_partial(part_path).await?;
+    Err(anyhow::Error::new(ChecksumMismatch {
+        expected: expected.clone(),
+        actual,
+    })
+    .context(format!("Content does not match the digest from {}", source)))
+}
+
+/// Check the size and piece digests given for the job, deleting the partial file when they
+/// differ so the retry starts over
+async fn verify_size_and_pieces(job: &Job, part_path: &Path) -> Result<()> {
+    let result = async {
+        if let Some(size) = job.size {
+            let actual = fs::metadata(part_path).await?.len();
+            if actual != size {
+                return Err(SizeMismatch {
+                    expected: size,
+                    actual,
+                }
+                .into());
+            }
+        }
+        if let Some(pieces) = &job.pieces {
+            verify_pieces(part_path, pieces).await?;
+        }
+        Ok(())
+    }
+    .await;
+    if result.is_err() {
+        remove_partial(part_path).await?;
+    }
+    result
+}
+
+#[allow(clippy::too_many_arguments)]
+/// Fetch the byte ranges of a file over separate connections, each retry of a range going to
+/// the next of `urls`. `validator` is only sent to the first of them, where it came from.
+async fn download_segments(
+    config: &Config,
+    client: &Client,
+    urls: &[Url],
+    headers: &HeaderMap,
+    validator: Option<String>,
+    path: &Path,
+    total: u64,
+    index: usize,
+    reporter: &Reporter,
+) -> Result<()> {
+    let file = fs::File::create(path).await?;
+    file.set_len(total).await?;
+    drop(file);
+
+    let count = (total / MIN_SEGMENT_SIZE).clamp(1, config.segments as u64);
+    let size = total.div_ceil(count);
+    let mut tasks = JoinSet::new();
+    for start in (0..total).step_by(size as usize) {
+        let retry = config.retry;
+        let headers = headers.clone();
+        let end = (start + size).min(total) - 1;
+        // Shared with every retry so a failed segment continues where it stopped
+        let written = Arc::new(AtomicU64::new(0));
+        let client = client.clone();
+        let urls = urls.to_vec();
+        let validator = validator.clone();
+        let path = path.to_path_buf();
+        let reporter = reporter.clone();
+        let mut attempt = 0;
+        let fetch = move || {
+            let n = attempt % urls.len();
+            attempt += 1;
+            download_segment(
+                client.clone(),
+                urls[n].clone(),
+                headers.clone(),
+                validator.clone().filter(|_| n == 0),
+                path.clone(),
+                start,
+                end,
+                written.clone(),
+                index,
+                reporter.clone(),
+            )
+        };
+        tasks.spawn(async move { fetch.retry(retry).await });
+    }
+    while let Some(result) = tasks.join_next().await {
+        result??;
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn download_segment(
+    client: Client,
+    url: Url,
+    headers: HeaderMap,
+    validator: Option<String>,
+    path: PathBuf,
+    start: u64,
+    end: u64,
+    written: Arc<AtomicU64>,
+    index: usize,
+    reporter: Reporter,
+) -> Result<()> {
+    let mut position = start + written.load(Ordering::Relaxed);
+    if position > end {
+        return Ok(());
+    }
+    let mut request_builder = client
+        .get(url)
+        .headers(headers)
+        .header(RANGE, format!("bytes={}-{}", position, end));
+    if let Some(v) = validator {
+        request_builder = request_builder.header(IF_RANGE, v);
+    }
+    let mut response = request_builder.send().await?.error_for_status()?;
+    if response.status() != StatusCode::PARTIAL_CONTENT
+        || get_range_start(response.headers()) != Some(position)
+    {
+        bail!("Server did not honour range {}-{}", position, end);
+    }
+
+    let mut file = fs::OpenOptions::new().write(true).open(&path).await?;
+    file.seek(SeekFrom::Start(position)).await?;
+    while let Some(chunk) = response.chunk().await? {
+        let remaining = (end + 1 - position) as usize;
+        let chunk = &chunk[..chunk.len().min(remaining)];
+        file.write_all(chunk).await?;
+        position += chunk.len() as u64;
+        written.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        reporter.send(Event::Received {
+            index,
+            bytes: chunk.len() as u64,
+        });
+        if position > end {
+            break;
+        }
+    }
+    file.flush().await?;
+    if position <= end {
+        return Err(anyhow::Error::new(BodyTruncated {
+            expected: end + 1 - start,
+            actual: position - start,
+        })
+        .context(format!("Segment {}-{} ended early", start, end)));
+    }
+    Ok(())
+}
+
+async fn send_range_request(
+    request_builder: RequestBuilder,
+    start: u64,
+    validator: &str,
+) -> Result<reqwest::Response> {
+    Ok(request_builder
+        .header(RANGE, format!("bytes={}-", start))
+        .header(IF_RANGE, validator)
+        .send()
+        .await?)
+}
+
+/// Strong validator suitable for `If-Range`, preferring the ETag
+fn get_validator(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(ETAG)
+        .and_then(|h| h.to_str().ok())
+        .filter(|etag| !etag.starts_with("W/"))
+        .or_else(|| headers.get(LAST_MODIFIED).and_then(|h| h.to_str().ok()))
+        .map(|v| v.to_string())
+}
+
+/// `ETag` and `Last-Modified` of a response, for conditional requests in later runs
+fn get_validators(headers: &HeaderMap) -> (Option<String>, Option<String>) {
+    let get = |name| {
+        headers
+            .get(name)
+            .and_then(|h| h.to_str().ok())
+            .map(|v| v.to_string())
+    };
+    (get(ETAG), get(LAST_MODIFIED))
+}
+
+fn get_range_start(headers: &HeaderMap) -> Option<u64> {
+    let content_range = headers.get(CONTENT_RANGE)?.to_str().ok()?;
+    let (_, start) = regex_captures!(r"^bytes (\d+)-", content_range)?;
+    start.parse().ok()
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Whether `path` was there before this run, rather than written by another job of it
+async fn existed_before(claims: &Claims, path: &Path, index: usize) -> Result<bool> {
+    Ok(!claims.is_claimed(path, index).await && fs::try_exists(path).await?)
+}
+
+fn skipped(path: PathBuf) -> Download {
+    Download {
+        path,
+        status: Status::Skipped,
+        etag: None,
+        last_modified: None,
+        http_status: None,
+        sha256: None,
+    }
+}
+
+async fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn get_display_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
@@ -0,0 +1,606 @@
+use std::{
+    path::PathBuf,
+    sync::{atomic::AtomicUsize, Arc},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use backon::{ExponentialBuilder, Retryable};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Client, StatusCode, Url,
+};
+use tokio::{fs, sync::mpsc, time::Instant};
+use tracing::{info, warn};
+
+use crate::{
+    checksum::{Checksum, PieceHashes},
+    conflict::{Claims, ConflictPolicy},
+    download::{download_image, is_retryable},
+    journal::Journal,
+    mime::ExtensionPolicy,
+    naming::Naming,
+    page::get_page_urls,
+    progress::{self, Event, Reporter},
+};
+
+/// One url to download
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub url: Url,
+    /// Line of the url in the list it was read from
+    pub line: Option<usize>,
+    /// Expected digest, a file that does not match is downloaded again
+    pub checksum: Option<Checksum>,
+    /// Path to save to, relative to the output folder, instead of the one from the naming
+    pub name: Option<PathBuf>,
+    /// Folder under the output folder to save into
+    pub dir: Option<PathBuf>,
+    /// Headers for this url only, replacing downloader headers of the same name
+    pub headers: HeaderMap,
+    /// Other urls serving the same file, tried in order when `url` fails
+    pub mirrors: Vec<Url>,
+    /// Expected size, a file of another size is downloaded again
+    pub size: Option<u64>,
+    /// Expected digests of the pieces of the file, checked like `checksum`
+    pub pieces: Option<PieceHashes>,
+}
+
+impl Job {
+    pub fn new(url: Url) -> Self {
+        Self {
+            url,
+            line: None,
+            checksum: None,
+            name: None,
+            dir: None,
+            headers: HeaderMap::new(),
+            mirrors: Vec::new(),
+            size: None,
+            pieces: None,
+        }
+    }
+
+    pub fn line(mut self, line: usize) -> Self {
+        self.line = Some(line);
+        self
+    }
+
+    pub fn checksum(mut self, checksum: Checksum) -> Self {
+        self.checksum = Some(checksum);
+        self
+    }
+
+    pub fn name(mut self, name: impl Into<PathBuf>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dir = Some(dir.into());
+        self
+    }
+
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn mirror(mut self, url: Url) -> Self {
+        self.mirrors.push(url);
+        self
+    }
+
+    pub fn size(mut self, size: u64) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    pub fn pieces(mut self, pieces: PieceHashes) -> Self {
+        self.pieces = Some(pieces);
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Downloaded,
+    /// Not fetched because the file name was taken and the conflict policy is skip, the file
+    /// already exists, or an earlier run already downloaded it
+    Skipped,
+    /// The server said the file from an earlier run is still current
+    Unchanged,
+}
+
+/// A job that did not fail
+#[derive(Debug, Clone)]
+pub struct Download {
+    pub path: PathBuf,
+    pub status: Status,
+    /// `ETag` of the response, sent back by later runs to ask whether the file changed
+    pub etag: Option<String>,
+    /// `Last-Modified` of the response, used like `etag`
+    pub last_modified: Option<String>,
+    /// Status of the response the file came from, `None` when nothing was transferred
+    pub http_status: Option<StatusCode>,
+    /// SHA-256 of the file, computed while saving it, `None` when nothing was saved
+    pub sha256: Option<Vec<u8>>,
+}
+
+/// What happened to a [`Job`], `index` is its position in the list given to [`Downloader::run`]
+#[derive(Debug)]
+pub struct JobResult {
+    pub index: usize,
+    pub job: Job,
+    pub outcome: Result<Download>,
+    /// Number of tries, 0 when no request was made
+    pub attempts: usize,
+    /// Time spent on the job, retries included
+    pub elapsed: Duration,
+}
+
+/// Settings shared by every download of a [`Downloader`]
+#[derive(Debug)]
+pub(crate) struct Config {
+    pub(crate) output: PathBuf,
+    pub(crate) jobs: usize,
+    pub(crate) delay: Option<Duration>,
+    pub(crate) headers: HeaderMap,
+    pub(crate) retry: ExponentialBuilder,
+    pub(crate) segments: u16,
+    pub(crate) naming: Naming,
+    pub(crate) extensions: ExtensionPolicy,
+    pub(crate) on_conflict: ConflictPolicy,
+    pub(crate) verify_server_digest: bool,
+    pub(crate) fsync: bool,
+    pub(crate) journal: bool,
+    pub(crate) resume: bool,
+    pub(crate) skip_existing: bool,
+    pub(crate) update: bool,
+    pub(crate) progress: bool,
+}
+
+/// State shared by the downloads of one [`Downloader::run`]
+#[derive(Debug)]
+pub(crate) struct Session {
+    pub(crate) client: Client,
+    pub(crate) config: Arc<Config>,
+    pub(crate) claims: Claims,
+    pub(crate) reporter: Reporter,
+    pub(crate) journal: Option<Journal>,
+}
+
+/// HTTP versions the shared client may use
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HttpVersion {
+    /// HTTP/2 when the server offers it through ALPN, HTTP/1.1 otherwise
+    #[default]
+    Auto,
+    Http1Only,
+    /// HTTP/2 without negotiation, only for servers known to support it
+    Http2PriorKnowledge,
+}
+
+#[derive(Debug)]
+pub struct DownloaderBuilder {
+    config: Config,
+    client: Option<Client>,
+    pool_max_idle_per_host: Option<usize>,
+    pool_idle_timeout: Option<Duration>,
+    http_version: HttpVersion,
+}
+
+impl Default for DownloaderBuilder {
+    fn default() -> Self {
+        Self {
+            client: None,
+            pool_max_idle_per_host: None,
+            pool_idle_timeout: None,
+            http_version: HttpVersion::default(),
+            config: Config {
+                output: PathBuf::from("."),
+                jobs: 4,
+                delay: None,
+                headers: HeaderMap::new(),
+                retry: ExponentialBuilder::default().with_max_times(5),
+                segments: 1,
+                naming: Naming::default(),
+                extensions: ExtensionPolicy::default(),
+                on_conflict: ConflictPolicy::default(),
+                verify_server_digest: false,
+                fsync: false,
+                journal: false,
+                resume: false,
+                skip_existing: false,
+                update: false,
+                progress: false,
+            },
+        }
+    }
+}
+
+impl DownloaderBuilder {
+    /// Folder the files are saved into, created if missing
+    pub fn output(mut self, output: impl Into<PathBuf>) -> Self {
+        self.config.output = output.into();
+        self
+    }
+
+    /// Number of concurrent downloads, at least 1
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.config.jobs = jobs.max(1);
+        self
+    }
+
+    /// Minimum time between the start of two requests
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.config.delay = Some(delay);
+        self
+    }
+
+    /// Header sent with every request
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.config.headers.insert(name, value);
+        self
+    }
+
+    pub fn headers(mut self, headers: HeaderMap) -> Self {
+        self.config.headers.extend(headers);
+        self
+    }
+
+    pub fn retry(mut self, retry: ExponentialBuilder) -> Self {
+        self.config.retry = retry;
+        self
+    }
+
+    /// Number of connections per file when the server supports ranges
+    pub fn segments(mut self, segments: u16) -> Self {
+        self.config.segments = segments.max(1);
+        self
+    }
+
+    pub fn naming(mut self, naming: Naming) -> Self {
+        self.config.naming = naming;
+        self
+    }
+
+    /// Whether file extensions are added or corrected from the Content-Type and the first bytes
+    pub fn extensions(mut self, policy: ExtensionPolicy) -> Self {
+        self.config.extensions = policy;
+        self
+    }
+
+    /// What to do when two downloads, or a download and an existing file, share a name
+    pub fn on_conflict(mut self, policy: ConflictPolicy) -> Self {
+        self.config.on_conflict = policy;
+        self
+    }
+
+    /// Check files against digests sent by the server, like `Content-MD5`, `Digest`,
+    /// `Repr-Digest`, `x-goog-hash` or an MD5 ETag
+    pub fn verify_server_digest(mut self, verify: bool) -> Self {
+        self.config.verify_server_digest = verify;
+        self
+    }
+
+    /// Flush each file to disk before it gets its final name, so it survives a power loss
+    pub fn fsync(mut self, fsync: bool) -> Self {
+        self.config.fsync = fsync;
+        self
+    }
+
+    /// Keep a journal of finished jobs in the output folder, updated as each one ends
+    pub fn journal(mut self, journal: bool) -> Self {
+        self.config.journal = journal;
+        self
+    }
+
+    /// Skip jobs that the journal of an earlier run lists as done. Implies [`Self::journal`]
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.config.resume = resume;
+        self
+    }
+
+    /// Do not download urls whose file is already in the output folder. With [`Self::update`],
+    /// files that an earlier run downloaded are checked with the server rather than skipped
+    pub fn skip_existing(mut self, skip_existing: bool) -> Self {
+        self.config.skip_existing = skip_existing;
+        self
+    }
+
+    /// Download again the files of an earlier run only when the server has a newer version,
+    /// using the `ETag` and `Last-Modified` kept in the journal. Implies [`Self::journal`]
+    pub fn update(mut self, update: bool) -> Self {
+        self.config.update = update;
+        self
+    }
+
+    /// Show a progress view on stderr, or periodic log lines when it is not a terminal
+    pub fn progress(mut self, progress: bool) -> Self {
+        self.config.progress = progress;
+        self
+    }
+
+    /// Use an existing client, the pool and HTTP version settings are then ignored
+    pub fn client(mut self, client: Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Maximum number of idle connections kept open for each host
+    pub fn pool_max_idle_per_host(mut self, max: usize) -> Self {
+        self.pool_max_idle_per_host = Some(max);
+        self
+    }
+
+    /// How long an idle connection is kept open for reuse
+    pub fn pool_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.pool_idle_timeout = Some(timeout);
+        self
+    }
+
+    pub fn http_version(mut self, version: HttpVersion) -> Self {
+        self.http_version = version;
+        self
+    }
+
+    pub fn build(self) -> Result<Downloader> {
+        let client = match self.client {
+            Some(client) => client,
+            None => {
+                let mut builder = Client::builder();
+                if let Some(max) = self.pool_max_idle_per_host {
+                    builder = builder.pool_max_idle_per_host(max);
+                }
+                if let Some(timeout) = self.pool_idle_timeout {
+                    builder = builder.pool_idle_timeout(timeout);
+                }
+                builder = match self.http_version {
+                    HttpVersion::Auto => builder,
+                    HttpVersion::Http1Only => builder.http1_only(),
+                    HttpVersion::Http2PriorKnowledge => builder.http2_prior_knowledge(),
+                };
+                builder.build()?
+            }
+        };
+        Ok(Downloader {
+            config: Arc::new(self.config),
+            client,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Downloader {
+    config: Arc<Config>,
+    /// Shared by every download so connections and TLS sessions are reused
+    client: Client,
+}
+
+impl Downloader {
+    pub fn builder() -> DownloaderBuilder {
+        DownloaderBuilder::default()
+    }
+
+    /// Download every job, returning one result per job in the original order
+    pub async fn run(&self, jobs: impl IntoIterator<Item = Job>) -> Vec<JobResult> {
+        let jobs: Vec<Job> = jobs.into_iter().collect();
+        let (sender, receiver) = mpsc::channel(jobs.len().max(1));
+        for job in jobs {
+            let _ = sender.try_send(job);
+        }
+        drop(sender);
+        self.run_channel(receiver).await
+    }
+
+    /// Fetch an HTML page with the headers and retries of the downloads, and collect the urls
+    /// it links to, see [`get_page_urls`]
+    pub async fn fetch_page(&self, url: &Url) -> Result<Vec<Url>> {
+        let fetch = || async {
+            let response = self
+                .client
+                .get(url.clone())
+                .headers(self.config.headers.clone())
+                .send()
+                .await?
+                .error_for_status()?;
+            // Relative links are resolved against where redirects ended up
+            let page_url = response.url().clone();
+            let html = response.text().await?;
+            anyhow::Ok(get_page_urls(&html, &page_url))
+        };
+        fetch.retry(self.config.retry).when(is_retryable).await
+    }
+
+    /// Download jobs as they arrive, until every sender of `jobs` is dropped.
+    ///
+    /// Indexes follow the order jobs were received in, and results are sorted by them.
+    pub async fn run_channel(&self, mut jobs: mpsc::Receiver<Job>) -> Vec<JobResult> {
+        if let Err(e) = fs::create_dir_all(&self.config.output).await {
+            let output = self.config.output.display();
+            let mut results = Vec::new();
+            while let Some(job) = jobs.recv().await {
+                results.push(JobResult {
+                    index: results.len(),
+                    job,
+                    outcome: Err(anyhow!("Cannot create {}: {}", output, e)),
+                    attempts: 0,
+                    elapsed: Duration::ZERO,
+                });
+            }
+            return results;
+        }
+        let config = &self.config;
+        let journal = if config.journal || config.resume || config.update {
+            match Journal::open(&config.output).await {
+                Ok(journal) => Some(journal),
+                Err(e) => {
+                    warn!("Cannot open the journal: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let (reporter, view) = if self.config.progress {
+            let (reporter, view) = progress::spawn();
+            (reporter, Some(view))
+        } else {
+            (Reporter::disabled(), None)
+        };
+
+        let session = Arc::new(Session {
+            client: self.client.clone(),
+            config: self.config.clone(),
+            claims: Claims::default(),
+            reporter,
+            journal,
+        });
+
+        // Number jobs as they arrive, so the view counts them before a worker is free
+        let (queue_sender, queue) = mpsc::unbounded_channel();
+        let reporter = session.reporter.clone();
+        tokio::spawn(async move {
+            let mut index = 0;
+            while let Some(job) = jobs.recv().await {
+                reporter.send(Event::Queued);
+                if queue_sender.send((index, job)).is_err() {
+                    break;
+                }
+                index += 1;
+            }
+        });
+
+        // Workers pull the next job from a shared queue when they finish one
+        let queue = Arc::new(tokio::sync::Mutex::new(queue));
+        let pacer = Arc::new(Pacer::new(self.config.delay));
+        let mut handles = Vec::new();
+        for _ in 0..self.config.jobs {
+            let queue = queue.clone();
+            let pacer = pacer.clone();
+            let session = session.clone();
+            let handle = tokio::spawn(async move {
+                let mut results = Vec::new();
+                loop {
+                    let Some((index, job)) = queue.lock().await.recv().await else {
+                        break;
+                    };
+                    // Files an update run should check again are not skipped
+                    let finished = session
+                        .journal
+                        .as_ref()
+                        .filter(|_| session.config.resume && !session.config.update)
+                        .and_then(|journal| journal.finished(&job));
+                    if let Some(previous) = finished {
+                        info!("Skip {}, done in an earlier run", previous.path.display());
+                        session.reporter.send(Event::Finished { index });
+                        results.push(JobResult {
+                            index,
+                            outcome: Ok(Download {
+                                path: previous.path.clone(),
+                                status: Status::Skipped,
+                                etag: previous.etag.clone(),
+                                last_modified: previous.last_modified.clone(),
+                                http_status: None,
+                                sha256: None,
+                            }),
+                            job,
+                            attempts: 0,
+                            elapsed: Duration::ZERO,
+                        });
+                        continue;
+                    }
+                    pacer.wait().await;
+                    let start = Instant::now();
+                    let mut attempts = 1;
+                    let outcome = {
+                        let retry = session.config.retry;
+                        let session = session.clone();
+                        let job = job.clone();
+                        // Shared by the retries, so each one goes on with the next mirror
+                        let next_url = &AtomicUsize::new(0);
+                        let download =
+                            move || download_image(session.clone(), job.clone(), index, next_url);
+                        download
+                            .retry(retry)
+                            .when(is_retryable)
+                            .notify(|_, _| attempts += 1)
+                            .await
+                    };
+                    match &outcome {
+                        Ok(download) => {
+                            match download.status {
+                                Status::Downloaded => info!("Saved {}", download.path.display()),
+                                Status::Skipped => info!("Skip {}", download.path.display()),
+                                Status::Unchanged => {
+                                    info!("Unchanged {}", download.path.display())
+                                }
+                            }
+                            session.reporter.send(Event::Finished { index });
+                        }
+                        Err(e) => {
+                            warn!("{:#}", e);
+                            session.reporter.send(Event::Failed { index });
+                        }
+                    }
+                    if let Some(journal) = &session.journal {
+                        if let Err(e) = journal.record(&job, &outcome).await {
+                            warn!("Cannot update the journal: {}", e);
+                        }
+                    }
+                    results.push(JobResult {
+                        index,
+                        job,
+                        outcome,
+                        attempts,
+                        elapsed: start.elapsed(),
+                    });
+                }
+                results
+            });
+            handles.push(handle);
+        }
+        drop(session);
+
+        let mut results = Vec::new();
+        for handle in handles.into_iter() {
+            match handle.await {
+                Ok(r) => results.extend(r),
+                Err(e) => warn!("{}", e),
+            }
+        }
+        if let Some(view) = view {
+            if let Err(e) = view.await {
+                warn!("{}", e);
+            }
+        }
+        results.sort_by_key(|r| r.index);
+        results
+    }
+}
+
+/// Spaces out the start of new requests across all workers
+struct Pacer {
+    delay: Option<Duration>,
+    next: tokio::sync::Mutex<Instant>,
+}
+
+impl Pacer {
+    fn new(delay: Option<Duration>) -> Self {
+        Self {
+            delay,
+            next: tokio::sync::Mutex::new(Instant::now()),
+        }
+    }
+
+    async fn wait(&self) {
+        let Some(delay) = self.delay else {
+            return;
+        };
+        let mut next = self.next.lock().await;
+        tokio::time::sleep_until(*next).await;
+        *next = Instant::now() + delay;
+    }
+}
@@ -0,0 +1,195 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex};
+use tracing::warn;
+
+use crate::downloader::{Download, Job, Status};
+
+/// Name of the journal inside the output folder
+pub(crate) const JOURNAL_FILE: &str = ".downall-state.jsonl";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum State {
+    Downloaded,
+    /// Found already there rather than written by downall
+    Skipped,
+    Unchanged,
+    Failed,
+}
+
+/// One line of the journal, the last line for a url is its current state
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    url: String,
+    state: State,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_modified: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// A url that an earlier run finished, whose file is still there
+#[derive(Debug)]
+pub(crate) struct Previous {
+    pub(crate) path: PathBuf,
+    state: State,
+    pub(crate) etag: Option<String>,
+    pub(crate) last_modified: Option<String>,
+    size: Option<u64>,
+    sha256: Option<String>,
+}
+
+impl Previous {
+    /// Whether the file was written by downall rather than found already there
+    pub(crate) fn downloaded(&self) -> bool {
+        self.state != State::Skipped
+    }
+}
+
+/// Record of finished jobs, appended to as each one ends so a crashed run can be resumed
+#[derive(Debug)]
+pub(crate) struct Journal {
+    file: Mutex<fs::File>,
+    done: HashMap<String, Previous>,
+}
+
+impl Journal {
+    /// Open the journal in `dir`, keeping what earlier runs did.
+    ///
+    /// Urls of this run replace their earlier lines, others stay so that a later run that
+    /// resumes or updates still knows about them.
+    pub(crate) async fn open(dir: &Path) -> Result<Self> {
+        let path = dir.join(JOURNAL_FILE);
+        let mut latest = HashMap::new();
+        let content = match fs::read_to_string(&path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        for (n, line) in content.lines().enumerate() {
+            // The last line may be cut short by a crash
+            match serde_json::from_str::<Entry>(line) {
+                Ok(entry) => {
+                    latest.insert(entry.url.clone(), entry);
+                }
+                Err(e) => warn!("Ignore line {} of {}: {}", n + 1, path.display(), e),
+            }
+        }
+
+        // Write back one line per url so the journal does not grow with every run
+        let mut content = String::new();
+        for entry in latest.values() {
+            content.push_str(&serde_json::to_string(entry)?);
+            content.push('\n');
+        }
+        let temp_path = dir.join(format!("{}.tmp", JOURNAL_FILE));
+        fs::write(&temp_path, content).await?;
+        fs::rename(&temp_path, &path).await?;
+
+        let mut done = HashMap::new();
+        for (url, entry) in latest {
+            let finished = matches!(
+                entry.state,
+                State::Downloaded | State::Skipped | State::Unchanged
+            );
+            let Some(path) = entry.path.filter(|_| finished) else {
+                continue;
+            };
+            if fs::try_exists(&path).await? {
+                done.insert(
+                    url,
+                    Previous {
+                        path,
+                        state: entry.state,
+                        etag: entry.etag,
+                        last_modified: entry.last_modified,
+                        size: entry.size,
+                        sha256: entry.sha256,
+                    },
+                );
+            }
+        }
+        let file = fs::OpenOptions::new().append(true).open(&path).await?;
+        Ok(Self {
+            file: Mutex::new(file),
+            done,
+        })
+    }
+
+    /// What an earlier run saved for `job`, if it did
+    pub(crate) fn finished(&self, job: &Job) -> Option<&Previous> {
+        self.done.get(job.url.as_str())
+    }
+
+    pub(crate) async fn record(&self, job: &Job, outcome: &Result<Download>) -> Result<()> {
+        let entry = match outcome {
+            Ok(download) => {
+                // Downloads are hashed while saved, files that were not downloaded keep what
+                // the journal knew about them rather than being read again. A skipped file
+                // that an earlier run downloaded stays downloaded, with its validators, so
+                // that an update run still asks the server about it.
+                let previous = self
+                    .finished(job)
+                    .filter(|previous| previous.path == download.path);
+                let (size, sha256) = match (download.status, previous) {
+                    (Status::Downloaded, _) | (_, None) => (
+                        fs::metadata(&download.path).await?.len(),
+                        download.sha256.as_deref().map(hex::encode),
+                    ),
+                    (_, Some(previous)) => match previous.size {
+                        Some(size) => (size, previous.sha256.clone()),
+                        None => (fs::metadata(&download.path).await?.len(), None),
+                    },
+                };
+                Entry {
+                    url: job.url.to_string(),
+                    state: match (download.status, previous) {
+                        (Status::Downloaded, _) => State::Downloaded,
+                        (Status::Skipped, Some(previous)) => previous.state,
+                        (Status::Skipped, None) => State::Skipped,
+                        (Status::Unchanged, _) => State::Unchanged,
+                    },
+                    path: Some(download.path.clone()),
+                    size: Some(size),
+                    sha256,
+                    etag: download.etag.clone().or_else(|| previous?.etag.clone()),
+                    last_modified: download
+                        .last_modified
+                        .clone()
+                        .or_else(|| previous?.last_modified.clone()),
+                    error: None,
+                }
+            }
+            Err(e) => Entry {
+                url: job.url.to_string(),
+                state: State::Failed,
+                path: None,
+                size: None,
+                sha256: None,
+                etag: None,
+                last_modified: None,
+                error: Some(format!("{:#}", e)),
+            },
+        };
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        let mut file = self.file.lock().await;
+        file.write_all(line.as_bytes()).await?;
+        file.flush().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,45 @@
+//! Bulk downloading files.
+//!
+//! Build a [`Downloader`], then hand it a list of [`Job`]s:
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use downall::{Downloader, Job};
+//!
+//! let downloader = Downloader::builder().output("images").jobs(8).build()?;
+//! let jobs = vec![Job::new("https://example.com/a.jpg".parse()?)];
+//! for result in downloader.run(jobs).await {
+//!     println!("{}: {:?}", result.job.url, result.outcome.map(|d| d.path));
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+mod checksum;
+mod conflict;
+mod download;
+mod downloader;
+mod journal;
+mod metalink;
+mod mime;
+mod naming;
+mod page;
+mod progress;
+mod report;
+mod template;
+mod urls;
+
+pub use checksum::{parse_checksum_file, Checksum, ChecksumMismatch, HashAlgorithm, PieceHashes};
+pub use conflict::{Conflict, ConflictPolicy};
+pub use download::{BodyTruncated, SizeMismatch};
+pub use downloader::{
+    Download, Downloader, DownloaderBuilder, HttpVersion, Job, JobResult, Status,
+};
+pub use metalink::parse_metalink;
+pub use mime::ExtensionPolicy;
+pub use naming::{NameContext, Naming};
+pub use page::{get_page_urls, LinkFilter};
+pub use progress::LogWriter;
+pub use report::{failed_list, ErrorClass, Rejected, Report, ReportEntry};
+pub use template::Template;
+pub use urls::{get_line_urls, get_urls, ListFormat, ListParser, ListedUrl};
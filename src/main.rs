@@ -1,20 +1,33 @@
-use std::{
-    fmt::Debug,
-    path::{Path, PathBuf},
-    time::Duration,
-};
+use std::{collections::HashMap, path::PathBuf, process::ExitCode, sync::Mutex, time::Duration};
 
-use anyhow::Result;
-use backon::{ExponentialBuilder, Retryable};
-use bytes::Bytes;
+use anyhow::{anyhow, Context, Result};
 use clap::Parser;
-use lazy_regex::{regex, regex_captures};
-use reqwest::{header::CONTENT_DISPOSITION, IntoUrl, Url};
-use tokio::fs;
-use tracing::{info, instrument, warn};
+use downall::{
+    failed_list, parse_checksum_file, Checksum, ConflictPolicy, Downloader, ExtensionPolicy,
+    HttpVersion, Job, LinkFilter, ListParser, ListedUrl, LogWriter, Naming, Rejected, Report,
+    Template,
+};
+use lazy_regex::Regex;
+use percent_encoding::percent_decode_str;
+use reqwest::header::{HeaderName, HeaderValue, REFERER};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    sync::mpsc,
+};
+use tracing::{info, warn};
+
+/// Some urls failed, or a list could not be read to the end
+const EXIT_PARTIAL_FAILURE: u8 = 2;
+/// Every url failed
+const EXIT_TOTAL_FAILURE: u8 = 3;
 
 #[derive(Debug, Clone, clap::Parser)]
-#[command(about, author, version)]
+#[command(
+    about,
+    author,
+    version,
+    after_help = "Exit status: 0 when every url succeeded, 1 on errors before downloading, 2 when some urls failed or a list could not be read to the end, 3 when all of them failed."
+)]
 struct Args {
     #[arg(short, long, help = "output folder", default_value = ".")]
     output: PathBuf,
@@ -22,88 +35,460 @@ struct Args {
     delay: Option<u64>,
     #[arg(short, long, help = "Set referer header")]
     referer: Option<String>,
-    #[arg(help = "file contains urls")]
-    url_list: PathBuf,
+    #[arg(short, long, help = "number of concurrent downloads", default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+    jobs: u16,
+    #[arg(short, long, help = "number of connections per file when the server supports ranges", default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    segments: u16,
+    #[arg(long, help = "maximum idle connections kept open per host")]
+    pool_size: Option<usize>,
+    #[arg(long, help = "close idle connections after this long (in second)")]
+    idle_timeout: Option<u64>,
+    #[arg(long, help = "HTTP version to use", value_enum, default_value_t = Http::Auto)]
+    http: Http,
+    #[arg(long, help = "what to do when a file name is already taken", value_enum, default_value_t = OnConflict::Rename)]
+    on_conflict: OnConflict,
+    #[arg(long, help = "check file extensions against the Content-Type and file content", value_enum, default_value_t = Extensions::Add)]
+    extensions: Extensions,
+    #[arg(
+        long,
+        help = "name files from a template, e.g. \"{host}/{index:04}.{ext}\"; placeholders: index, line, host, path, stem, ext, sha256, date"
+    )]
+    name_template: Option<Template>,
+    #[arg(
+        long,
+        help = "recreate the remote host and directories under the output folder",
+        conflicts_with = "name_template"
+    )]
+    mirror: bool,
+    #[arg(
+        long,
+        help = "file of expected digests in SHA256SUMS format, matched by the file name in the url"
+    )]
+    checksums: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "check files against digest headers sent by the server (Content-MD5, Digest, Repr-Digest, x-goog-hash, MD5 ETag)"
+    )]
+    verify_digest: bool,
+    #[arg(long, help = "flush each file to disk before giving it its final name")]
+    fsync: bool,
+    #[arg(
+        long,
+        help = "only download urls that an earlier run into the same output folder did not finish"
+    )]
+    resume: bool,
+    #[arg(
+        long,
+        help = "do not download urls whose file is already in the output folder, with --update files of an earlier run are checked rather than skipped"
+    )]
+    skip_existing: bool,
+    #[arg(
+        long,
+        help = "download files of an earlier run again only when the server has a newer version"
+    )]
+    update: bool,
+    #[arg(long, help = "write the outcome of every url to this JSON file")]
+    report: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "where to list failed urls, in a format downall reads back [default: failed.txt in the output folder]"
+    )]
+    failed_list: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "treat the urls as HTML pages and download the images, videos and links on them"
+    )]
+    from_page: bool,
+    #[arg(
+        long,
+        help = "with --from-page, only download links with one of these extensions, e.g. jpg,png",
+        value_delimiter = ',',
+        requires = "from_page"
+    )]
+    accept: Vec<String>,
+    #[arg(
+        long,
+        help = "with --from-page, only download links matching this regex",
+        requires = "from_page"
+    )]
+    accept_regex: Option<Regex>,
+    #[arg(
+        required = true,
+        help = "urls, files that contain urls, or - to read urls from stdin; lists may be free text, JSON Lines, CSV, TOML, aria2c input or Metalink files"
+    )]
+    inputs: Vec<String>,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Http {
+    /// negotiate HTTP/2 when the server offers it
+    Auto,
+    /// only use HTTP/1.1
+    Http1,
+    /// use HTTP/2 without negotiation
+    Http2,
+}
 
-    let args = Args::parse();
-    let urls = get_urls(&args.url_list).await?;
-    let mut handles = Vec::new();
-    for url in urls.into_iter() {
-        let referer = args.referer.clone();
-        let download_url = move || download_image(url.clone(), referer.clone());
-        let handle = tokio::spawn(async move {
-            download_url
-                .retry(ExponentialBuilder::default().with_max_times(5))
-                .await
-        });
-        handles.push(handle);
-        if let Some(d) = args.delay {
-            tokio::time::sleep(Duration::from_millis(d)).await;
+impl From<Http> for HttpVersion {
+    fn from(value: Http) -> Self {
+        match value {
+            Http::Auto => HttpVersion::Auto,
+            Http::Http1 => HttpVersion::Http1Only,
+            Http::Http2 => HttpVersion::Http2PriorKnowledge,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum OnConflict {
+    /// replace the existing file
+    Overwrite,
+    /// keep the existing file and do not download
+    Skip,
+    /// add a counter, "image (1).jpg"
+    Rename,
+    /// fail the download
+    Error,
+    /// add a hash of the url, "image-1a2b3c4d.jpg"
+    HashSuffix,
+}
+
+impl From<OnConflict> for ConflictPolicy {
+    fn from(value: OnConflict) -> Self {
+        match value {
+            OnConflict::Overwrite => ConflictPolicy::Overwrite,
+            OnConflict::Skip => ConflictPolicy::Skip,
+            OnConflict::Rename => ConflictPolicy::Rename,
+            OnConflict::Error => ConflictPolicy::Error,
+            OnConflict::HashSuffix => ConflictPolicy::HashSuffix,
         }
     }
+}
 
-    fs::create_dir_all(&args.output).await?;
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Extensions {
+    /// leave names alone
+    Keep,
+    /// add an extension to names that have none
+    Add,
+    /// also replace extensions that do not match the content
+    Fix,
+}
 
-    for (i, handle) in handles.into_iter().enumerate() {
-        match handle.await {
-            Ok(Ok((name, data))) => {
-                fs::write(
-                    args.output.join(name.unwrap_or(format!("file_{}", i))),
-                    data,
-                )
-                .await?;
+impl From<Extensions> for ExtensionPolicy {
+    fn from(value: Extensions) -> Self {
+        match value {
+            Extensions::Keep => ExtensionPolicy::Keep,
+            Extensions::Add => ExtensionPolicy::AddMissing,
+            Extensions::Fix => ExtensionPolicy::Correct,
+        }
+    }
+}
+
+/// A url argument, or a list of urls to read
+enum Input {
+    Url(String),
+    List(Box<dyn AsyncRead + Send + Unpin>),
+}
+
+/// Turns listed urls into jobs for the downloader
+struct Queue<'a> {
+    sender: mpsc::Sender<Job>,
+    checksums: HashMap<String, Checksum>,
+    /// With --from-page, listed urls are pages and their links are downloaded instead
+    pages: Option<Pages<'a>>,
+    /// Listed urls that failed before becoming jobs, reported along with the failed jobs
+    rejected: &'a Mutex<Vec<Rejected>>,
+}
+
+struct Pages<'a> {
+    downloader: &'a Downloader,
+    filter: LinkFilter,
+    /// Send each page as the referer of its links, unless --referer is given
+    referer: bool,
+}
+
+impl Queue<'_> {
+    /// Queue a listed url, or the links of the page at it, false once the downloader is gone
+    async fn push(&self, listed: ListedUrl, line: Option<usize>) -> bool {
+        let Some(pages) = &self.pages else {
+            return match get_job(listed, line, &self.checksums) {
+                Ok(job) => self.sender.send(job).await.is_ok(),
+                Err(rejected) => self.reject(*rejected),
+            };
+        };
+        let page = match listed.url.parse::<reqwest::Url>() {
+            Ok(page) => page,
+            Err(e) => {
+                warn!("Skip {}: {}", listed.url, e);
+                let error = anyhow!(e).context("Invalid url");
+                return self.reject(Rejected { listed, error });
+            }
+        };
+        let links = match pages.downloader.fetch_page(&page).await {
+            Ok(links) => links,
+            Err(e) => {
+                warn!("Cannot read page {}: {:#}", page, e);
+                let error = e.context("Cannot read page");
+                return self.reject(Rejected { listed, error });
+            }
+        };
+        let links: Vec<_> = links
+            .into_iter()
+            .filter(|link| pages.filter.matches(link))
+            .collect();
+        info!("Found {} links on {}", links.len(), page);
+        for link in links {
+            let listed = ListedUrl {
+                url: link.to_string(),
+                referer: pages.referer.then(|| page.to_string()),
+                ..Default::default()
+            };
+            match get_job(listed, line, &self.checksums) {
+                Ok(job) => {
+                    if self.sender.send(job).await.is_err() {
+                        return false;
+                    }
+                }
+                Err(rejected) => {
+                    self.reject(*rejected);
+                }
             }
-            Ok(Err(e)) => warn!("{}", e),
-            Err(e) => warn!("{}", e),
         }
+        true
     }
 
-    Ok(())
+    /// Keep a url that failed before it became a job, true to go on with the next one
+    fn reject(&self, rejected: Rejected) -> bool {
+        self.rejected.lock().unwrap().push(rejected);
+        true
+    }
+
+    /// Queue the urls of a list, line by line as they become available
+    async fn read_list(&self, list: impl AsyncRead + Unpin) -> Result<()> {
+        let mut reader = BufReader::new(list);
+        let mut parser = ListParser::new();
+        let mut buffer = Vec::new();
+        while reader.read_until(b'\n', &mut buffer).await? > 0 {
+            // A stray byte that is not UTF-8 should not cut the list short
+            let text = String::from_utf8_lossy(&buffer);
+            let text = text.trim_end_matches(['\n', '\r']);
+            let urls = parser.push_line(text);
+            buffer.clear();
+            for listed in urls {
+                let line = listed.line;
+                if !self.push(listed, Some(line)).await {
+                    return Ok(());
+                }
+            }
+        }
+        for listed in parser.finish() {
+            let line = listed.line;
+            if !self.push(listed, Some(line)).await {
+                break;
+            }
+        }
+        Ok(())
+    }
 }
 
-#[instrument]
-async fn download_image<T: IntoUrl + Debug>(
-    url: T,
-    referer: Option<String>,
-) -> Result<(Option<String>, Bytes)> {
-    let url = url.into_url()?;
-    info!("Process url {}", url.to_string());
-    let client = reqwest::Client::new();
-    let mut request_builder = client.get(url.clone());
-    request_builder = if let Some(r) = referer {
-        request_builder.header("referer", r)
-    } else {
-        request_builder
+/// Job for a listed url, with the digest given in the list or else the one from the sums file
+fn get_job(
+    listed: ListedUrl,
+    line: Option<usize>,
+    checksums: &HashMap<String, Checksum>,
+) -> Result<Job, Box<Rejected>> {
+    let parse = |url: &str| {
+        url.parse::<reqwest::Url>()
+            .map_err(|e| warn!("Skip {}: {}", url, e))
+            .ok()
     };
-    let response = request_builder.send().await?.error_for_status()?;
-    let headers = response.headers().clone();
-    let file_name = if let Some(h) = headers.get(CONTENT_DISPOSITION) {
-        if let Some((_, file_name)) = regex_captures!(r#"filename="(.*?)""#, h.to_str()?) {
-            Some(file_name)
-        } else {
-            get_file_name_from_url(&url)
+    let url = match listed.url.parse::<reqwest::Url>() {
+        Ok(url) => url,
+        Err(e) => {
+            warn!("Skip {}: {}", listed.url, e);
+            let error = anyhow!(e).context("Invalid url");
+            return Err(Box::new(Rejected { listed, error }));
         }
-    } else {
-        get_file_name_from_url(&url)
     };
-    let data = response.bytes().await?;
-    Ok((file_name.map(|x| x.to_string()), data))
+    let name = url
+        .path_segments()
+        .and_then(|mut s| s.next_back())
+        .map(|s| percent_decode_str(s).decode_utf8_lossy().into_owned());
+    let checksum = listed
+        .checksum
+        .or_else(|| name.and_then(|name| checksums.get(&name).cloned()));
+    let mut job = Job::new(url);
+    if let Some(line) = line {
+        job = job.line(line);
+    }
+    if let Some(checksum) = checksum {
+        job = job.checksum(checksum);
+    }
+    if let Some(name) = listed.name {
+        job = job.name(name);
+    }
+    if let Some(dir) = listed.dir {
+        job = job.dir(dir);
+    }
+    let headers = listed
+        .referer
+        .map(|referer| (REFERER.to_string(), referer))
+        .into_iter()
+        .chain(listed.headers);
+    for (name, value) in headers {
+        match (HeaderName::try_from(&name), HeaderValue::try_from(&value)) {
+            (Ok(name), Ok(value)) => job = job.header(name, value),
+            _ => warn!("Ignore header {} of {}", name, job.url),
+        }
+    }
+    if let Some(size) = listed.size {
+        job = job.size(size);
+    }
+    if let Some(pieces) = listed.pieces {
+        job = job.pieces(pieces);
+    }
+    for mirror in listed.mirrors {
+        if let Some(mirror) = parse(&mirror) {
+            job = job.mirror(mirror);
+        }
+    }
+    Ok(job)
 }
 
-fn get_file_name_from_url(url: &Url) -> Option<&str> {
-    url.path_segments().map(|s| s.last()).flatten()
-}
+#[tokio::main]
+async fn main() -> Result<ExitCode> {
+    tracing_subscriber::fmt()
+        .with_writer(LogWriter::default)
+        .init();
+
+    let args = Args::parse();
+    let mut builder = Downloader::builder()
+        .output(&args.output)
+        .jobs(args.jobs as usize)
+        .segments(args.segments)
+        .http_version(args.http.into())
+        .on_conflict(args.on_conflict.into())
+        .extensions(args.extensions.into())
+        .verify_server_digest(args.verify_digest)
+        .fsync(args.fsync)
+        .journal(true)
+        .resume(args.resume)
+        .skip_existing(args.skip_existing)
+        .update(args.update)
+        .progress(true);
+    if let Some(d) = args.delay {
+        builder = builder.delay(Duration::from_millis(d));
+    }
+    if let Some(r) = &args.referer {
+        builder = builder.header(REFERER, HeaderValue::from_str(r)?);
+    }
+    if let Some(template) = &args.name_template {
+        builder = builder.naming(Naming::Template(template.clone()));
+    }
+    if args.mirror {
+        builder = builder.naming(Naming::Mirror);
+    }
+    if let Some(size) = args.pool_size {
+        builder = builder.pool_max_idle_per_host(size);
+    }
+    if let Some(t) = args.idle_timeout {
+        builder = builder.pool_idle_timeout(Duration::from_secs(t));
+    }
+    let downloader = builder.build()?;
+
+    let checksums = match &args.checksums {
+        Some(path) => parse_checksum_file(&tokio::fs::read_to_string(path).await?),
+        None => Default::default(),
+    };
+
+    // Open every file first, so a typo fails the run before anything is downloaded
+    let mut inputs = Vec::new();
+    for input in &args.inputs {
+        inputs.push(if input == "-" {
+            Input::List(Box::new(tokio::io::stdin()))
+        } else if input.starts_with("http://") || input.starts_with("https://") {
+            Input::Url(input.clone())
+        } else {
+            let file = tokio::fs::File::open(input)
+                .await
+                .with_context(|| format!("Cannot open {}", input))?;
+            Input::List(Box::new(file))
+        });
+    }
+
+    let pages = args.from_page.then(|| {
+        let mut filter = LinkFilter::new().extensions(&args.accept);
+        if let Some(pattern) = &args.accept_regex {
+            filter = filter.pattern(pattern.clone());
+        }
+        Pages {
+            downloader: &downloader,
+            filter,
+            referer: args.referer.is_none(),
+        }
+    });
+
+    // Downloads start while the lists are still being read
+    let (sender, receiver) = mpsc::channel(64);
+    let rejected = Mutex::new(Vec::new());
+    let queue = Queue {
+        sender,
+        checksums,
+        pages,
+        rejected: &rejected,
+    };
+    let reader = async move {
+        for input in inputs {
+            match input {
+                Input::Url(url) => {
+                    let listed = ListedUrl {
+                        url,
+                        ..Default::default()
+                    };
+                    if !queue.push(listed, None).await {
+                        break;
+                    }
+                }
+                Input::List(list) => queue.read_list(list).await?,
+            }
+        }
+        anyhow::Ok(())
+    };
+    let (results, read) = tokio::join!(downloader.run_channel(receiver), reader);
+    // Urls after a read error were never tried, so the run cannot count as a success
+    let read_all = match read {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Cannot read every url: {:#}", e);
+            false
+        }
+    };
+
+    let rejected = rejected.into_inner().unwrap();
+    let report = Report::new(&results, &rejected).await;
+    if let Some(path) = &args.report {
+        report.write(path).await?;
+    }
+    let failed_path = args
+        .failed_list
+        .unwrap_or_else(|| args.output.join("failed.txt"));
+    if report.failed > 0 {
+        tokio::fs::write(&failed_path, failed_list(&results, &rejected)).await?;
+        warn!(
+            "{} of {} urls failed, see {}",
+            report.failed,
+            report.total,
+            failed_path.display()
+        );
+    } else if read_all && tokio::fs::try_exists(&failed_path).await? {
+        // Left by an earlier run, its urls have all been downloaded now
+        tokio::fs::remove_file(&failed_path).await?;
+    }
 
-async fn get_urls(path: &Path) -> Result<Vec<String>> {
-    let content = fs::read_to_string(path).await?;
-    let pattern = regex!(r#"(https?://\S+[.!,;\?'\"]?)\s"#);
-    Ok(pattern
-        .captures_iter(&content)
-        .map(|c| c.get(1).unwrap().as_str().to_string())
-        .collect())
+    Ok(match report.failed {
+        0 if read_all => ExitCode::SUCCESS,
+        n if n > 0 && n == report.total => ExitCode::from(EXIT_TOTAL_FAILURE),
+        _ => ExitCode::from(EXIT_PARTIAL_FAILURE),
+    })
 }
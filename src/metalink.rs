@@ -0,0 +1,117 @@
+use anyhow::{bail, Result};
+use roxmltree::{Document, Node};
+use tracing::warn;
+
+use crate::{
+    checksum::{strength, Checksum, HashAlgorithm, PieceHashes},
+    urls::ListedUrl,
+};
+
+/// Collect the files of a Metalink document, either RFC 5854 (`.meta4`) or version 3
+/// (`.metalink`), with the line of their `<file>` element.
+///
+/// Urls are ordered by priority, the first one becomes the url and the others its mirrors.
+/// Only http(s) urls are kept.
+pub fn parse_metalink(content: &str) -> Result<Vec<ListedUrl>> {
+    let document = Document::parse(content)?;
+    let root = document.root_element();
+    if root.tag_name().name() != "metalink" {
+        bail!(
+            "Expected a metalink document, got <{}>",
+            root.tag_name().name()
+        );
+    }
+    let mut files = Vec::new();
+    for file in root.descendants().filter(|n| is(n, "file")) {
+        let line = document.text_pos_at(file.range().start).row as usize;
+        let Some(name) = file.attribute("name") else {
+            warn!("Skip line {}: file without a name", line);
+            continue;
+        };
+
+        // Version 3 ranks by preference, highest first, RFC 5854 by priority, lowest first
+        let mut urls: Vec<_> = file
+            .descendants()
+            .filter(|n| is(n, "url"))
+            .filter_map(|url| {
+                let rank = match (url.attribute("priority"), url.attribute("preference")) {
+                    (Some(priority), _) => priority.parse().unwrap_or(u32::MAX),
+                    (None, Some(preference)) => {
+                        u32::MAX - preference.parse::<u32>().unwrap_or_default()
+                    }
+                    (None, None) => u32::MAX,
+                };
+                Some((rank, url.text()?.trim()))
+            })
+            .filter(|(_, url)| url.starts_with("http://") || url.starts_with("https://"))
+            .collect();
+        urls.sort_by_key(|(rank, _)| *rank);
+        let mut urls = urls.into_iter().map(|(_, url)| url.to_string());
+        let Some(url) = urls.next() else {
+            warn!("Skip line {}: no http(s) url for {}", line, name);
+            continue;
+        };
+
+        let size = file
+            .children()
+            .find(|n| is(n, "size"))
+            .and_then(|n| n.text()?.trim().parse().ok());
+        let checksum = file
+            .descendants()
+            .filter(|n| is(n, "hash") && !n.parent().is_some_and(|p| is(&p, "pieces")))
+            .filter_map(|hash| get_checksum(&hash, line))
+            .max_by_key(|checksum| strength(checksum.algorithm));
+        let pieces = file
+            .descendants()
+            .filter(|n| is(n, "pieces"))
+            .filter_map(|pieces| get_pieces(&pieces, line))
+            .max_by_key(|pieces| strength(pieces.algorithm));
+
+        files.push(ListedUrl {
+            url,
+            line,
+            checksum,
+            name: Some(name.to_string()),
+            mirrors: urls.collect(),
+            size,
+            pieces,
+            ..Default::default()
+        });
+    }
+    Ok(files)
+}
+
+/// Whether a node is an element named `name`, in whichever namespace
+fn is(node: &Node, name: &str) -> bool {
+    node.is_element() && node.tag_name().name() == name
+}
+
+/// Digest of a `<hash type="sha-256">` element, `None` for algorithms downall does not have
+fn get_checksum(hash: &Node, line: usize) -> Option<Checksum> {
+    let algorithm: HashAlgorithm = hash.attribute("type")?.parse().ok()?;
+    Checksum::from_hex(algorithm, hash.text()?)
+        .map_err(|e| warn!("Ignore hash of line {}: {}", line, e))
+        .ok()
+}
+
+fn get_pieces(pieces: &Node, line: usize) -> Option<PieceHashes> {
+    let algorithm: HashAlgorithm = pieces.attribute("type")?.parse().ok()?;
+    let length = pieces
+        .attribute("length")?
+        .parse()
+        .ok()
+        .filter(|l| *l > 0)?;
+    let digests = pieces
+        .children()
+        .filter(|n| is(n, "hash"))
+        .map(|hash| Checksum::from_hex(algorithm, hash.text().unwrap_or_default()))
+        .map(|checksum| checksum.map(|checksum| checksum.digest))
+        .collect::<Result<Vec<_>>>()
+        .map_err(|e| warn!("Ignore piece hashes of line {}: {}", line, e))
+        .ok()?;
+    Some(PieceHashes {
+        length,
+        algorithm,
+        digests,
+    })
+}
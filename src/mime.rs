@@ -0,0 +1,167 @@
+use std::path::{Path, PathBuf};
+
+use crate::naming::fit_path;
+
+/// Whether the extension of a file name is checked against its content
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExtensionPolicy {
+    /// Leave names alone
+    Keep,
+    /// Add an extension to names that have none
+    #[default]
+    AddMissing,
+    /// Also replace extensions that do not match the content
+    Correct,
+}
+
+/// Known types as `(mime type, extensions)`, the first extension is the one added
+const TYPES: &[(&str, &[&str])] = &[
+    ("image/jpeg", &["jpg", "jpeg", "jpe", "jfif"]),
+    ("image/png", &["png"]),
+    ("image/gif", &["gif"]),
+    ("image/webp", &["webp"]),
+    ("image/avif", &["avif"]),
+    ("image/heic", &["heic", "heif"]),
+    ("image/bmp", &["bmp"]),
+    ("image/tiff", &["tif", "tiff"]),
+    ("image/svg+xml", &["svg"]),
+    ("image/x-icon", &["ico"]),
+    ("image/vnd.microsoft.icon", &["ico"]),
+    ("video/mp4", &["mp4", "m4v"]),
+    ("video/webm", &["webm"]),
+    ("video/x-matroska", &["mkv"]),
+    ("video/quicktime", &["mov"]),
+    ("video/x-msvideo", &["avi"]),
+    ("video/mp2t", &["ts"]),
+    ("audio/mpeg", &["mp3"]),
+    ("audio/mp4", &["m4a"]),
+    ("audio/ogg", &["ogg", "oga"]),
+    ("audio/flac", &["flac"]),
+    ("audio/wav", &["wav"]),
+    ("audio/x-wav", &["wav"]),
+    ("application/pdf", &["pdf"]),
+    ("application/zip", &["zip"]),
+    ("application/gzip", &["gz", "tgz"]),
+    ("application/x-gzip", &["gz", "tgz"]),
+    ("application/x-7z-compressed", &["7z"]),
+    ("application/vnd.rar", &["rar"]),
+    ("application/x-rar-compressed", &["rar"]),
+    ("application/x-tar", &["tar"]),
+    ("application/json", &["json"]),
+    ("application/xml", &["xml"]),
+    ("text/xml", &["xml"]),
+    ("application/javascript", &["js", "mjs"]),
+    ("text/javascript", &["js", "mjs"]),
+    ("text/html", &["html", "htm"]),
+    ("text/css", &["css"]),
+    ("text/csv", &["csv"]),
+    ("text/plain", &["txt"]),
+];
+
+/// Extension for a `Content-Type` header value, `None` for generic binary types
+pub(crate) fn extension_from_mime(content_type: &str) -> Option<&'static str> {
+    let mime = content_type.split(';').next()?.trim().to_ascii_lowercase();
+    TYPES
+        .iter()
+        .find(|(m, _)| *m == mime)
+        .map(|(_, extensions)| extensions[0])
+}
+
+/// Extension for the first bytes of a file, from well known signatures
+pub(crate) fn sniff_extension(bytes: &[u8]) -> Option<&'static str> {
+    let starts = |prefix: &[u8]| bytes.starts_with(prefix);
+    let at = |offset: usize, tag: &[u8]| bytes.get(offset..offset + tag.len()) == Some(tag);
+    let extension = if starts(b"\x89PNG\r\n\x1a\n") {
+        "png"
+    } else if starts(b"\xff\xd8\xff") {
+        "jpg"
+    } else if starts(b"GIF87a") || starts(b"GIF89a") {
+        "gif"
+    } else if starts(b"RIFF") && at(8, b"WEBP") {
+        "webp"
+    } else if starts(b"RIFF") && at(8, b"WAVE") {
+        "wav"
+    } else if starts(b"RIFF") && at(8, b"AVI ") {
+        "avi"
+    } else if at(4, b"ftyp") {
+        match bytes.get(8..12) {
+            Some(b"avif") | Some(b"avis") => "avif",
+            Some(b"heic") | Some(b"heix") | Some(b"mif1") => "heic",
+            Some(b"qt  ") => "mov",
+            Some(b"M4A ") => "m4a",
+            _ => "mp4",
+        }
+    } else if starts(b"\x1a\x45\xdf\xa3") {
+        if bytes.windows(4).any(|w| w == b"webm") {
+            "webm"
+        } else {
+            "mkv"
+        }
+    } else if starts(b"%PDF-") {
+        "pdf"
+    } else if starts(b"PK\x03\x04") || starts(b"PK\x05\x06") {
+        "zip"
+    } else if starts(b"\x1f\x8b") {
+        "gz"
+    } else if starts(b"7z\xbc\xaf\x27\x1c") {
+        "7z"
+    } else if starts(b"Rar!\x1a\x07") {
+        "rar"
+    } else if at(257, b"ustar") {
+        "tar"
+    } else if starts(b"OggS") {
+        "ogg"
+    } else if starts(b"fLaC") {
+        "flac"
+    } else if starts(b"ID3") || starts(b"\xff\xfb") || starts(b"\xff\xf3") {
+        "mp3"
+    } else if starts(b"BM") && bytes.len() > 14 {
+        "bmp"
+    } else if starts(b"II*\0") || starts(b"MM\0*") {
+        "tif"
+    } else if starts(b"\0\0\x01\0") {
+        "ico"
+    } else {
+        return None;
+    };
+    Some(extension)
+}
+
+/// Add or replace the extension of the last component of `path` to match `detected`
+pub(crate) fn fix_extension(
+    path: PathBuf,
+    detected: Option<&str>,
+    policy: ExtensionPolicy,
+) -> PathBuf {
+    let Some(detected) = detected else {
+        return path;
+    };
+    let path = match (policy, get_extension(&path)) {
+        (ExtensionPolicy::Keep, _) => path,
+        (_, None) => {
+            let mut name = path.into_os_string();
+            name.push(".");
+            name.push(detected);
+            PathBuf::from(name)
+        }
+        (ExtensionPolicy::Correct, Some(ext)) if !is_same_type(&ext, detected) => {
+            path.with_extension(detected)
+        }
+        _ => path,
+    };
+    fit_path(path)
+}
+
+fn get_extension(path: &Path) -> Option<String> {
+    path.extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+        .filter(|ext| !ext.is_empty())
+}
+
+/// Whether two extensions name the same kind of file, like `jpeg` and `jpg`
+fn is_same_type(ext: &str, other: &str) -> bool {
+    ext == other
+        || TYPES
+            .iter()
+            .any(|(_, extensions)| extensions.contains(&ext) && extensions.contains(&other))
+}
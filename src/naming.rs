@@ -0,0 +1,369 @@
+use std::{
+    fmt,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use encoding_rs::Encoding;
+use percent_encoding::percent_decode;
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::{downloader::Job, template::Template};
+
+/// What is known about a download when its file name is chosen
+#[derive(Debug)]
+pub struct NameContext<'a> {
+    pub index: usize,
+    pub job: &'a Job,
+    /// Name suggested by the server, or taken from the url
+    pub suggested: Option<&'a str>,
+    /// Extension matching the Content-Type or the first bytes of the body
+    pub extension: Option<&'a str>,
+}
+
+/// Longest file name written, extension and conflict suffix included, which leaves room
+/// for the `.part.meta` suffix of partial downloads under the usual 255 byte limit
+pub(crate) const MAX_NAME_BYTES: usize = 255 - ".part.meta".len();
+
+/// How downloaded files are named, relative to the output folder.
+///
+/// Whatever a naming returns is sanitized, so the file always ends up inside the output folder.
+#[derive(Clone, Default)]
+pub enum Naming {
+    /// The suggested name, or `file_{index}` when there is none
+    #[default]
+    Suggested,
+    Template(Template),
+    /// Recreate the remote layout, `https://host/a/b/c.jpg` becomes `host/a/b/c.jpg`
+    Mirror,
+    Custom(Arc<dyn Fn(&NameContext) -> PathBuf + Send + Sync>),
+}
+
+impl Naming {
+    pub fn custom(f: impl Fn(&NameContext) -> PathBuf + Send + Sync + 'static) -> Self {
+        Self::Custom(Arc::new(f))
+    }
+
+    /// Safe path relative to the output folder, a name or folder set on the job wins
+    pub(crate) fn resolve(&self, context: &NameContext) -> PathBuf {
+        let path = match (&context.job.name, self) {
+            (Some(name), _) => name.clone(),
+            (None, Naming::Suggested) => context.suggested.map(PathBuf::from).unwrap_or_default(),
+            (None, Naming::Template(template)) => PathBuf::from(template.render(context)),
+            (None, Naming::Mirror) => get_mirror_path(&context.job.url),
+            (None, Naming::Custom(f)) => f(context),
+        };
+        let path = match &context.job.dir {
+            Some(dir) => dir.join(path),
+            None => path,
+        };
+        let path = sanitize_path(&path);
+        if path.as_os_str().is_empty() {
+            PathBuf::from(format!("file_{}", context.index))
+        } else {
+            path
+        }
+    }
+}
+
+impl fmt::Debug for Naming {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Naming::Suggested => write!(f, "Suggested"),
+            Naming::Template(template) => f.debug_tuple("Template").field(template).finish(),
+            Naming::Mirror => write!(f, "Mirror"),
+            Naming::Custom(_) => write!(f, "Custom(..)"),
+        }
+    }
+}
+
+/// Last usable path segment of the url, percent-decoded, or else its host
+pub(crate) fn get_file_name_from_url(url: &Url) -> Option<String> {
+    url.path_segments()
+        .into_iter()
+        .flat_map(|segments| segments.rev())
+        .map(|segment| {
+            percent_decode(segment.as_bytes())
+                .decode_utf8_lossy()
+                .into_owned()
+        })
+        .chain(url.host_str().map(|host| host.to_string()))
+        .find_map(|name| sanitize_file_name(&name))
+}
+
+/// `host/a/b/c.jpg` for `https://host/a/b/c.jpg`.
+///
+/// Directory urls are saved as `index.html`, and a query adds a hash of its
+/// sorted parameters to the name, so `c.jpg?b=2&a=1` and `c.jpg?a=1&b=2` match.
+fn get_mirror_path(url: &Url) -> PathBuf {
+    let mut path = PathBuf::new();
+    match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => path.push(format!("{}_{}", host, port)),
+        (Some(host), None) => path.push(host),
+        _ => {}
+    }
+    let segments: Vec<String> = url
+        .path_segments()
+        .into_iter()
+        .flatten()
+        .map(|segment| {
+            percent_decode(segment.as_bytes())
+                .decode_utf8_lossy()
+                .into_owned()
+        })
+        .collect();
+    let (name, directories) = match segments.split_last() {
+        Some((name, directories)) if !name.is_empty() => (name.clone(), directories),
+        Some((_, directories)) => ("index.html".to_string(), directories),
+        None => ("index.html".to_string(), &[][..]),
+    };
+    path.extend(directories);
+
+    let mut pairs: Vec<_> = url.query_pairs().collect();
+    if pairs.is_empty() {
+        path.push(name);
+    } else {
+        pairs.sort();
+        let query = pairs
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join("&");
+        let hash = format!("{:x}", Sha256::digest(query.as_bytes()));
+        let name = Path::new(&name);
+        let stem = name.file_stem().unwrap_or_default().to_string_lossy();
+        path.push(match name.extension() {
+            Some(ext) => format!("{}-{}.{}", stem, &hash[..8], ext.to_string_lossy()),
+            None => format!("{}-{}", stem, &hash[..8]),
+        });
+    }
+    path
+}
+
+/// Keep only normal components of `path`, each sanitized, so it cannot leave the folder it is joined to
+pub(crate) fn sanitize_path(path: &Path) -> PathBuf {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => sanitize_file_name(&name.to_string_lossy()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Single safe file name, or `None` when nothing usable is left.
+///
+/// Only the part after the last path separator is kept. Control characters
+/// and characters Windows rejects are dropped, leading and trailing dots are
+/// trimmed, and the name is NFC normalized and truncated to [`MAX_NAME_BYTES`].
+pub(crate) fn sanitize_file_name(name: &str) -> Option<String> {
+    let name = name
+        .rsplit(['/', '\\'])
+        .find(|part| !part.trim().trim_matches('.').is_empty())?;
+    let name: String = name
+        .nfc()
+        .filter(|c| !c.is_control())
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c => c,
+        })
+        .collect();
+    let name = name.trim().trim_matches('.').trim();
+    if name.is_empty() {
+        return None;
+    }
+    Some(truncate_file_name(name))
+}
+
+/// Cut the stem so the name fits in [`MAX_NAME_BYTES`], keeping a short extension
+fn truncate_file_name(name: &str) -> String {
+    let (stem, ext) = match name.rfind('.') {
+        Some(i) if i > 0 && name.len() - i <= 16 => name.split_at(i),
+        _ => (name, ""),
+    };
+    fit_file_name(stem, ext)
+}
+
+/// `stem` followed by `tail`, with the stem cut on a character boundary so the whole
+/// fits in [`MAX_NAME_BYTES`]
+pub(crate) fn fit_file_name(stem: &str, tail: &str) -> String {
+    let mut end = stem.len().min(MAX_NAME_BYTES.saturating_sub(tail.len()));
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &stem[..end], tail)
+}
+
+/// Truncate the last component of `path` once something was added to it, like an extension
+pub(crate) fn fit_path(path: PathBuf) -> PathBuf {
+    match path.file_name().map(|name| name.to_string_lossy()) {
+        Some(name) if name.len() > MAX_NAME_BYTES => {
+            let name = truncate_file_name(&name);
+            path.with_file_name(name)
+        }
+        _ => path,
+    }
+}
+
+/// File name from a `Content-Disposition` header value, following RFC 6266.
+///
+/// `filename*` (RFC 5987) wins over `filename`. Header bytes that are not
+/// UTF-8 are read as ISO-8859-1, like browsers do.
+pub(crate) fn parse_content_disposition(value: &[u8]) -> Option<String> {
+    let mut filename = None;
+    let mut filename_ext = None;
+    for (name, value) in parse_parameters(value) {
+        match name.to_ascii_lowercase().as_str() {
+            "filename*" => filename_ext = decode_ext_value(&value).or(filename_ext),
+            "filename" => filename = Some(decode_plain_value(&value)),
+            _ => {}
+        }
+    }
+    filename_ext.or(filename).filter(|name| !name.is_empty())
+}
+
+/// `name=value` pairs after the disposition type, with quoted strings unescaped
+fn parse_parameters(value: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let mut parameters = Vec::new();
+    let Some(start) = value.iter().position(|b| *b == b';') else {
+        return parameters;
+    };
+    let mut rest = &value[start..];
+    loop {
+        rest = trim_start(rest, |b| b == b';' || b.is_ascii_whitespace());
+        if rest.is_empty() {
+            break;
+        }
+        let end = rest
+            .iter()
+            .position(|b| *b == b'=' || *b == b';')
+            .unwrap_or(rest.len());
+        let name = String::from_utf8_lossy(&rest[..end]).trim().to_string();
+        rest = &rest[end..];
+        if rest.first() != Some(&b'=') {
+            continue;
+        }
+        rest = trim_start(&rest[1..], |b| b.is_ascii_whitespace());
+
+        let mut parameter = Vec::new();
+        if rest.first() == Some(&b'"') {
+            let mut i = 1;
+            while i < rest.len() && rest[i] != b'"' {
+                if rest[i] == b'\\' && i + 1 < rest.len() {
+                    i += 1;
+                }
+                parameter.push(rest[i]);
+                i += 1;
+            }
+            rest = &rest[(i + 1).min(rest.len())..];
+        } else {
+            let end = rest.iter().position(|b| *b == b';').unwrap_or(rest.len());
+            parameter.extend_from_slice(rest[..end].trim_ascii_end());
+            rest = &rest[end..];
+        }
+        parameters.push((name, parameter));
+    }
+    parameters
+}
+
+fn trim_start(bytes: &[u8], f: impl Fn(u8) -> bool) -> &[u8] {
+    let start = bytes.iter().position(|b| !f(*b)).unwrap_or(bytes.len());
+    &bytes[start..]
+}
+
+/// RFC 5987 `charset'language'percent-encoded`
+fn decode_ext_value(value: &[u8]) -> Option<String> {
+    let mut parts = value.splitn(3, |b| *b == b'\'');
+    let charset = String::from_utf8_lossy(parts.next()?).trim().to_string();
+    let _language = parts.next()?;
+    let bytes: Vec<u8> = percent_decode(parts.next()?).collect();
+    let encoding = if charset.is_empty() {
+        encoding_rs::UTF_8
+    } else {
+        Encoding::for_label(charset.as_bytes())?
+    };
+    let (name, _) = encoding.decode_without_bom_handling(&bytes);
+    Some(name.into_owned())
+}
+
+/// Plain `filename`, which some servers percent-encode even though they should not
+fn decode_plain_value(value: &[u8]) -> String {
+    let decoded: Vec<u8> = percent_decode(value).collect();
+    if decoded != value {
+        if let Ok(name) = String::from_utf8(decoded) {
+            return name;
+        }
+    }
+    match std::str::from_utf8(value) {
+        Ok(name) => name.to_string(),
+        Err(_) => encoding_rs::WINDOWS_1252.decode(value).0.into_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_path_stays_inside() {
+        assert_eq!(
+            sanitize_path(Path::new("../../.bashrc")),
+            PathBuf::from("bashrc")
+        );
+        assert_eq!(
+            sanitize_path(Path::new("/etc/passwd")),
+            PathBuf::from("etc/passwd")
+        );
+        assert_eq!(sanitize_path(Path::new("a/../b")), PathBuf::from("a/b"));
+        assert_eq!(sanitize_path(Path::new("..")), PathBuf::new());
+    }
+
+    #[test]
+    fn sanitize_file_name_drops_separators() {
+        assert_eq!(
+            sanitize_file_name("../../.bashrc").as_deref(),
+            Some("bashrc")
+        );
+        assert_eq!(
+            sanitize_file_name("C:\\Windows\\win.ini").as_deref(),
+            Some("win.ini")
+        );
+        assert_eq!(sanitize_file_name("/etc/"), Some("etc".to_string()));
+        assert_eq!(sanitize_file_name(".."), None);
+    }
+
+    #[test]
+    fn sanitize_file_name_drops_control_characters() {
+        assert_eq!(
+            sanitize_file_name("a\0b\nc\x7f.jpg").as_deref(),
+            Some("abc.jpg")
+        );
+        assert_eq!(sanitize_file_name("\0\t\r"), None);
+        assert_eq!(sanitize_file_name("a:b?.txt").as_deref(), Some("a_b_.txt"));
+    }
+
+    #[test]
+    fn truncate_keeps_utf8_and_extension() {
+        let name = format!("{}.jpg", "画".repeat(100));
+        let truncated = sanitize_file_name(&name).unwrap();
+        assert!(truncated.len() <= MAX_NAME_BYTES);
+        assert!(truncated.ends_with(".jpg"));
+        assert!(truncated
+            .trim_end_matches(".jpg")
+            .chars()
+            .all(|c| c == '画'));
+    }
+
+    #[test]
+    fn suffixes_stay_within_limit() {
+        let name = sanitize_file_name(&"a".repeat(300)).unwrap();
+        let path = fit_path(PathBuf::from("dir").join(format!("{}.webp", name)));
+        let name = path.file_name().unwrap().to_string_lossy();
+        assert!(name.len() <= MAX_NAME_BYTES && name.ends_with(".webp"));
+        let renamed = fit_file_name(name.trim_end_matches(".webp"), " (1).webp");
+        assert_eq!(renamed.len(), MAX_NAME_BYTES);
+        assert!(renamed.ends_with(" (1).webp"));
+        assert!(format!("{}.part.meta", renamed).len() <= 255);
+    }
+}
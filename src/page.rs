@@ -0,0 +1,156 @@
+use std::collections::HashSet;
+
+use lazy_regex::{regex, Regex};
+use reqwest::Url;
+use scraper::{ElementRef, Html, Selector};
+
+/// Attributes that lazy loading scripts copy into `src` once the element is on screen
+const LAZY_SRC: [&str; 5] = [
+    "data-src",
+    "data-original",
+    "data-lazy-src",
+    "data-lazy",
+    "data-full-src",
+];
+const LAZY_SRCSET: [&str; 2] = ["data-srcset", "data-lazy-srcset"];
+
+/// Collect the http(s) urls an HTML page links to or embeds, in page order and each once.
+///
+/// Urls come from `<img>`, `<source>`, `<video>` and `<audio>` sources, the largest
+/// candidate of each `srcset`, lazy loading attributes like `data-src`, `<a href>`, and
+/// CSS `url()` in `<style>` and `style` attributes. Relative urls are resolved against
+/// `<base href>` when the page has one, `page_url` otherwise.
+pub fn get_page_urls(html: &str, page_url: &Url) -> Vec<Url> {
+    let document = Html::parse_document(html);
+    let base = document
+        .select(&Selector::parse("base[href]").unwrap())
+        .next()
+        .and_then(|base| page_url.join(base.value().attr("href")?.trim()).ok())
+        .unwrap_or_else(|| page_url.clone());
+
+    let mut found = Vec::new();
+    for element in document
+        .root_element()
+        .descendants()
+        .filter_map(ElementRef::wrap)
+    {
+        let attr = |name: &str| element.value().attr(name).map(String::from);
+        match element.value().name() {
+            "img" | "source" | "audio" => found.extend(attr("src")),
+            "video" => found.extend(attr("src").into_iter().chain(attr("poster"))),
+            "a" => found.extend(attr("href")),
+            "style" => found.extend(get_css_urls(&element.text().collect::<String>())),
+            _ => {}
+        }
+        if matches!(element.value().name(), "img" | "source") {
+            found.extend(attr("srcset").and_then(|srcset| get_largest_candidate(&srcset)));
+        }
+        found.extend(LAZY_SRC.into_iter().filter_map(attr));
+        found.extend(
+            LAZY_SRCSET
+                .into_iter()
+                .filter_map(attr)
+                .filter_map(|srcset| get_largest_candidate(&srcset)),
+        );
+        if let Some(style) = attr("style") {
+            found.extend(get_css_urls(&style));
+        }
+    }
+
+    let mut seen = HashSet::new();
+    found
+        .into_iter()
+        .filter_map(|url| base.join(url.trim()).ok())
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .map(|mut url| {
+            url.set_fragment(None);
+            url
+        })
+        .filter(|url| seen.insert(url.clone()))
+        .collect()
+}
+
+/// Url of the widest candidate of a `srcset`, or the densest when they give densities
+fn get_largest_candidate(srcset: &str) -> Option<String> {
+    let mut candidates = Vec::new();
+    let mut rest = srcset;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+        if rest.is_empty() {
+            break;
+        }
+        // Commas inside a url, like `w_100,h_100`, belong to it, one at its end ends it
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let (url, after) = rest.split_at(end);
+        let (descriptor, next) = match url.strip_suffix(',') {
+            Some(_) => ("", after),
+            None => after.split_once(',').unwrap_or((after, "")),
+        };
+        candidates.push((url.trim_end_matches(','), descriptor.trim()));
+        rest = next;
+    }
+    candidates
+        .into_iter()
+        .filter_map(|(url, descriptor)| {
+            let descriptor = descriptor.split_whitespace().next().unwrap_or("1x");
+            let (size, is_width) = match descriptor.strip_suffix('w') {
+                Some(width) => (width.parse::<f64>().ok()?, true),
+                None => (descriptor.strip_suffix('x')?.parse::<f64>().ok()?, false),
+            };
+            (size.is_finite() && size > 0.0).then_some((is_width, size, url))
+        })
+        .max_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)))
+        .map(|(_, _, url)| url.to_string())
+}
+
+fn get_css_urls(css: &str) -> Vec<String> {
+    regex!(r#"url\(\s*['"]?([^'")\s]+)['"]?\s*\)"#)
+        .captures_iter(css)
+        .map(|c| c[1].to_string())
+        .filter(|url| !url.starts_with("data:"))
+        .collect()
+}
+
+/// Which links of a page to download, all of them unless narrowed down
+#[derive(Debug, Clone, Default)]
+pub struct LinkFilter {
+    extensions: Vec<String>,
+    pattern: Option<Regex>,
+}
+
+impl LinkFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only keep urls whose file name has one of these extensions, compared ignoring case
+    pub fn extensions(mut self, extensions: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.extensions = extensions
+            .into_iter()
+            .map(|e| e.into().trim_start_matches('.').to_ascii_lowercase())
+            .collect();
+        self
+    }
+
+    /// Only keep urls that match `pattern` somewhere
+    pub fn pattern(mut self, pattern: Regex) -> Self {
+        self.pattern = Some(pattern);
+        self
+    }
+
+    pub fn matches(&self, url: &Url) -> bool {
+        if !self.extensions.is_empty() {
+            let extension = url
+                .path_segments()
+                .and_then(|mut s| s.next_back())
+                .and_then(|name| name.rsplit_once('.'))
+                .map(|(_, extension)| extension.to_ascii_lowercase());
+            if !extension.is_some_and(|e| self.extensions.contains(&e)) {
+                return false;
+            }
+        }
+        self.pattern
+            .as_ref()
+            .is_none_or(|pattern| pattern.is_match(url.as_str()))
+    }
+}
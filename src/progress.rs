@@ -0,0 +1,273 @@
+use std::{
+    collections::HashMap,
+    io::{IsTerminal, Write},
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+
+use indicatif::{HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressStyle};
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+};
+use tracing::info;
+
+const LOG_INTERVAL: Duration = Duration::from_secs(5);
+const TICK_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Bars of the terminal view, shared with [`LogWriter`] so log lines do not tear them
+static BARS: LazyLock<MultiProgress> = LazyLock::new(MultiProgress::new);
+
+/// Something that happened to one download, identified by its index in the url list
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// A job was added to the batch
+    Queued,
+    /// A (possibly resumed) attempt started, `position` bytes are already on disk
+    Started {
+        index: usize,
+        name: String,
+        size: Option<u64>,
+        position: u64,
+    },
+    Received {
+        index: usize,
+        bytes: u64,
+    },
+    Finished {
+        index: usize,
+    },
+    Failed {
+        index: usize,
+    },
+}
+
+/// Cheap handle that download tasks use to emit progress events
+#[derive(Debug, Clone)]
+pub struct Reporter {
+    sender: Option<UnboundedSender<Event>>,
+}
+
+impl Reporter {
+    /// Reporter that drops every event
+    pub fn disabled() -> Self {
+        Self { sender: None }
+    }
+
+    pub fn send(&self, event: Event) {
+        // The view only goes away once every download is done
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(event);
+        }
+    }
+}
+
+/// Start the progress view for a batch of downloads, which grows with each [`Event::Queued`].
+///
+/// The view ends after every [`Reporter`] has been dropped.
+pub fn spawn() -> (Reporter, JoinHandle<()>) {
+    let (sender, receiver) = unbounded_channel();
+    let handle = tokio::spawn(render(receiver));
+    (
+        Reporter {
+            sender: Some(sender),
+        },
+        handle,
+    )
+}
+
+/// Log writer for stderr that hides the progress view while a line is written, as in
+/// `tracing_subscriber::fmt().with_writer(LogWriter::default)`
+#[derive(Debug, Default)]
+pub struct LogWriter {
+    buffer: Vec<u8>,
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        BARS.suspend(|| {
+            let mut stderr = std::io::stderr().lock();
+            stderr.write_all(&self.buffer)?;
+            stderr.flush()
+        })?;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+impl Drop for LogWriter {
+    /// The log layer takes a writer per event, so this writes one whole event at a time
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    total: usize,
+    completed: usize,
+    failed: usize,
+    bytes: u64,
+}
+
+impl Stats {
+    fn throughput(&self, elapsed: Duration) -> u64 {
+        (self.bytes as f64 / elapsed.as_secs_f64().max(0.001)) as u64
+    }
+
+    fn eta(&self, elapsed: Duration) -> Option<Duration> {
+        let done = self.completed + self.failed;
+        if done == 0 {
+            return None;
+        }
+        Some(elapsed.mul_f64((self.total - done) as f64 / done as f64))
+    }
+
+    fn summary(&self, elapsed: Duration) -> String {
+        format!(
+            "{} failed, {}/s",
+            self.failed,
+            HumanBytes(self.throughput(elapsed))
+        )
+    }
+}
+
+async fn render(mut receiver: UnboundedReceiver<Event>) {
+    let mut stats = Stats::default();
+    let start = Instant::now();
+    let mut view = std::io::stderr().is_terminal().then(View::new);
+    let mut ticker = tokio::time::interval(if view.is_some() {
+        TICK_INTERVAL
+    } else {
+        LOG_INTERVAL
+    });
+    ticker.tick().await;
+
+    loop {
+        tokio::select! {
+            event = receiver.recv() => {
+                let Some(event) = event else {
+                    break;
+                };
+                match &event {
+                    Event::Queued => stats.total += 1,
+                    Event::Received { bytes, .. } => stats.bytes += bytes,
+                    Event::Finished { .. } => stats.completed += 1,
+                    Event::Failed { .. } => stats.failed += 1,
+                    Event::Started { .. } => {}
+                }
+                if let Some(view) = view.as_mut() {
+                    view.apply(event, &stats);
+                }
+            }
+            _ = ticker.tick() => {
+                let elapsed = start.elapsed();
+                match view.as_ref() {
+                    Some(view) => view.overall.set_message(stats.summary(elapsed)),
+                    None => log_line(&stats, elapsed),
+                }
+            }
+        }
+    }
+
+    let elapsed = start.elapsed();
+    match view {
+        Some(view) => view.overall.finish_with_message(stats.summary(elapsed)),
+        None => log_line(&stats, elapsed),
+    }
+}
+
+fn log_line(stats: &Stats, elapsed: Duration) {
+    info!(
+        "{}/{} done, {}, eta {}",
+        stats.completed,
+        stats.total,
+        stats.summary(elapsed),
+        stats
+            .eta(elapsed)
+            .map(|d| HumanDuration(d).to_string())
+            .unwrap_or("unknown".to_string())
+    );
+}
+
+/// Terminal view with a bar for the whole batch and one for each in-flight file
+struct View {
+    multi: MultiProgress,
+    overall: ProgressBar,
+    files: HashMap<usize, ProgressBar>,
+}
+
+impl View {
+    fn new() -> Self {
+        let multi = BARS.clone();
+        let overall = multi.add(ProgressBar::new(0));
+        overall.set_style(
+            ProgressStyle::with_template(
+                "[{elapsed_precise}] {bar:40} {pos}/{len} ({msg}) eta {eta}",
+            )
+            .unwrap(),
+        );
+        Self {
+            multi,
+            overall,
+            files: HashMap::new(),
+        }
+    }
+
+    fn apply(&mut self, event: Event, stats: &Stats) {
+        match event {
+            Event::Queued => self.overall.inc_length(1),
+            Event::Started {
+                index,
+                name,
+                size,
+                position,
+            } => {
+                let bar = self
+                    .files
+                    .entry(index)
+                    .or_insert_with(|| self.multi.add(ProgressBar::no_length()));
+                match size {
+                    Some(size) => {
+                        bar.set_style(
+                            ProgressStyle::with_template(
+                                "{wide_msg} {bar:30} {bytes}/{total_bytes} {bytes_per_sec}",
+                            )
+                            .unwrap(),
+                        );
+                        bar.set_length(size);
+                    }
+                    None => bar.set_style(
+                        ProgressStyle::with_template(
+                            "{wide_msg} {spinner} {bytes} {bytes_per_sec}",
+                        )
+                        .unwrap(),
+                    ),
+                }
+                bar.set_message(name);
+                bar.set_position(position);
+            }
+            Event::Received { index, bytes } => {
+                if let Some(bar) = self.files.get(&index) {
+                    bar.inc(bytes);
+                }
+            }
+            Event::Finished { index } | Event::Failed { index } => {
+                if let Some(bar) = self.files.remove(&index) {
+                    bar.finish_and_clear();
+                    self.multi.remove(&bar);
+                }
+                self.overall
+                    .set_position((stats.completed + stats.failed) as u64);
+            }
+        }
+    }
+}
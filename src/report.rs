@@ -0,0 +1,248 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use lazy_regex::regex;
+use reqwest::StatusCode;
+use serde::Serialize;
+use tokio::fs;
+
+use crate::{
+    checksum::ChecksumMismatch,
+    conflict::Conflict,
+    download::{BodyTruncated, SizeMismatch},
+    downloader::{JobResult, Status},
+    urls::{Entry, ListedUrl},
+};
+
+/// Kind of failure, so scripts can tell a dead link from a flaky network
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorClass {
+    /// The server answered with an error status
+    Http,
+    Timeout,
+    /// The server could not be reached
+    Connect,
+    /// The connection broke while reading the body, or it ended early
+    Body,
+    /// Other request errors, like redirect loops
+    Request,
+    /// The content did not match its expected digest
+    Checksum,
+    /// The content did not have the size given in the url list
+    Size,
+    /// The file name was taken and the conflict policy is error
+    Conflict,
+    /// Writing the file failed
+    Io,
+    Other,
+}
+
+impl ErrorClass {
+    pub fn of(error: &anyhow::Error) -> Self {
+        for cause in error.chain() {
+            if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+                return if e.is_status() {
+                    ErrorClass::Http
+                } else if e.is_timeout() {
+                    ErrorClass::Timeout
+                } else if e.is_connect() {
+                    ErrorClass::Connect
+                } else if e.is_body() || e.is_decode() {
+                    ErrorClass::Body
+                } else {
+                    ErrorClass::Request
+                };
+            }
+            if cause.is::<BodyTruncated>() {
+                return ErrorClass::Body;
+            }
+            if cause.is::<ChecksumMismatch>() {
+                return ErrorClass::Checksum;
+            }
+            if cause.is::<SizeMismatch>() {
+                return ErrorClass::Size;
+            }
+            if cause.is::<Conflict>() {
+                return ErrorClass::Conflict;
+            }
+            if cause.is::<std::io::Error>() {
+                return ErrorClass::Io;
+            }
+        }
+        ErrorClass::Other
+    }
+}
+
+impl fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ErrorClass::Http => "http",
+            ErrorClass::Timeout => "timeout",
+            ErrorClass::Connect => "connect",
+            ErrorClass::Body => "body",
+            ErrorClass::Request => "request",
+            ErrorClass::Checksum => "checksum",
+            ErrorClass::Size => "size",
+            ErrorClass::Conflict => "conflict",
+            ErrorClass::Io => "io",
+            ErrorClass::Other => "other",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A listed url that failed before it became a job, like one that does not parse or a page
+/// whose links could not be read
+#[derive(Debug)]
+pub struct Rejected {
+    pub listed: ListedUrl,
+    pub error: anyhow::Error,
+}
+
+/// Outcome of every job of a run, meant to be written as JSON
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub total: usize,
+    pub downloaded: usize,
+    pub skipped: usize,
+    pub unchanged: usize,
+    pub failed: usize,
+    pub results: Vec<ReportEntry>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReportEntry {
+    pub url: String,
+    pub line: Option<usize>,
+    /// `downloaded`, `skipped`, `unchanged` or `failed`
+    pub status: &'static str,
+    pub http_status: Option<u16>,
+    pub error_class: Option<ErrorClass>,
+    pub error: Option<String>,
+    pub path: Option<PathBuf>,
+    /// Size of the saved file
+    pub bytes: Option<u64>,
+    pub duration_ms: u64,
+    /// Tries after the first one
+    pub retries: usize,
+}
+
+impl Report {
+    /// Rejected urls count as failed, after the jobs
+    pub async fn new(results: &[JobResult], rejected: &[Rejected]) -> Self {
+        let mut report = Report {
+            total: results.len() + rejected.len(),
+            downloaded: 0,
+            skipped: 0,
+            unchanged: 0,
+            failed: 0,
+            results: Vec::with_capacity(results.len() + rejected.len()),
+        };
+        for result in results {
+            let mut entry = ReportEntry {
+                url: result.job.url.to_string(),
+                line: result.job.line,
+                status: "failed",
+                http_status: None,
+                error_class: None,
+                error: None,
+                path: None,
+                bytes: None,
+                duration_ms: result.elapsed.as_millis() as u64,
+                retries: result.attempts.saturating_sub(1),
+            };
+            match &result.outcome {
+                Ok(download) => {
+                    entry.status = match download.status {
+                        Status::Downloaded => {
+                            report.downloaded += 1;
+                            "downloaded"
+                        }
+                        Status::Skipped => {
+                            report.skipped += 1;
+                            "skipped"
+                        }
+                        Status::Unchanged => {
+                            report.unchanged += 1;
+                            "unchanged"
+                        }
+                    };
+                    entry.http_status = download.http_status.map(|s| s.as_u16());
+                    entry.path = Some(download.path.clone());
+                    entry.bytes = fs::metadata(&download.path).await.ok().map(|m| m.len());
+                }
+                Err(e) => {
+                    report.failed += 1;
+                    entry.http_status = get_http_status(e).map(|s| s.as_u16());
+                    entry.error_class = Some(ErrorClass::of(e));
+                    entry.error = Some(format!("{:#}", e));
+                }
+            }
+            report.results.push(entry);
+        }
+        for rejected in rejected {
+            report.failed += 1;
+            report.results.push(ReportEntry {
+                url: rejected.listed.url.clone(),
+                line: Some(rejected.listed.line).filter(|line| *line > 0),
+                status: "failed",
+                http_status: get_http_status(&rejected.error).map(|s| s.as_u16()),
+                error_class: Some(ErrorClass::of(&rejected.error)),
+                error: Some(format!("{:#}", rejected.error)),
+                path: None,
+                bytes: None,
+                duration_ms: 0,
+                retries: 0,
+            });
+        }
+        report
+    }
+
+    pub async fn write(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?).await?;
+        Ok(())
+    }
+}
+
+/// Failed jobs and rejected urls as a JSON Lines list that [`get_urls`](crate::get_urls)
+/// reads back with every per-url option, each entry after a comment with the reason
+pub fn failed_list(results: &[JobResult], rejected: &[Rejected]) -> String {
+    let failed = results.iter().filter_map(|result| {
+        let e = result.outcome.as_ref().err()?;
+        Some((e, Entry::from_job(&result.job)))
+    });
+    let rejected = rejected
+        .iter()
+        .map(|rejected| (&rejected.error, Entry::from_listed(&rejected.listed)));
+    let mut list = String::new();
+    for (e, entry) in failed.chain(rejected) {
+        list.push_str("# ");
+        list.push_str(&get_reason(e));
+        list.push('\n');
+        // An entry of plain strings and numbers always serializes
+        list.push_str(&serde_json::to_string(&entry).unwrap());
+        list.push('\n');
+    }
+    list
+}
+
+fn get_http_status(error: &anyhow::Error) -> Option<StatusCode> {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<reqwest::Error>()?.status())
+}
+
+/// One line reason, without urls to keep it short
+fn get_reason(error: &anyhow::Error) -> String {
+    let class = ErrorClass::of(error);
+    if let (ErrorClass::Http, Some(status)) = (class, get_http_status(error)) {
+        return format!("{}: {}", class, status);
+    }
+    let message = format!("{:#}", error).replace(['\r', '\n'], " ");
+    let message = regex!(r"https?://\S+").replace_all(&message, "<url>");
+    format!("{}: {}", class, message)
+}
@@ -0,0 +1,177 @@
+use std::{
+    path::Path,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Result};
+use percent_encoding::percent_decode;
+use sha2::{Digest, Sha256};
+
+use crate::naming::NameContext;
+
+/// Output naming template such as `{host}/{index:04}.{ext}`.
+///
+/// Placeholders, `:N` pads numbers with zeros to `N` digits and cuts hashes to `N` characters:
+/// - `{index}` position of the url in the list, starting at 0
+/// - `{line}` line of the url in the list file, or the index when unknown
+/// - `{host}` host of the url
+/// - `{path}` directories of the url path, percent-decoded
+/// - `{stem}` suggested name without its extension
+/// - `{ext}` detected extension, or else the one of the suggested name
+/// - `{sha256}` SHA-256 of the url, so names are stable across runs
+/// - `{date}` download date as `YYYY-MM-DD` (UTC)
+///
+/// `/` starts a subdirectory, `{{` and `}}` are literal braces.
+#[derive(Debug, Clone)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Literal(String),
+    Placeholder { field: Field, width: Option<usize> },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Field {
+    Index,
+    Line,
+    Host,
+    Path,
+    Stem,
+    Ext,
+    Sha256,
+    Date,
+}
+
+impl FromStr for Template {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => placeholder.push(c),
+                            None => bail!("Unmatched {{ in template {}", s),
+                        }
+                    }
+                    let (name, width) = match placeholder.split_once(':') {
+                        Some((name, width)) => match width.parse() {
+                            Ok(width) => (name, Some(width)),
+                            Err(_) => bail!("Invalid width in {{{}}}", placeholder),
+                        },
+                        None => (placeholder.as_str(), None),
+                    };
+                    let field = match name.trim() {
+                        "index" => Field::Index,
+                        "line" => Field::Line,
+                        "host" => Field::Host,
+                        "path" => Field::Path,
+                        "stem" => Field::Stem,
+                        "ext" => Field::Ext,
+                        "sha256" => Field::Sha256,
+                        "date" => Field::Date,
+                        _ => bail!("Unknown placeholder {{{}}}", placeholder),
+                    };
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Placeholder { field, width });
+                }
+                '}' => bail!("Unmatched }} in template {}", s),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Self { parts })
+    }
+}
+
+impl Template {
+    pub(crate) fn render(&self, context: &NameContext) -> String {
+        let url = &context.job.url;
+        let suggested = context.suggested.map(Path::new);
+        let mut output = String::new();
+        for part in &self.parts {
+            let (field, width) = match part {
+                Part::Literal(literal) => {
+                    output.push_str(literal);
+                    continue;
+                }
+                Part::Placeholder { field, width } => (*field, *width),
+            };
+            let number = |n: usize| format!("{:0width$}", n, width = width.unwrap_or(0));
+            let value = match field {
+                Field::Index => number(context.index),
+                Field::Line => number(context.job.line.unwrap_or(context.index)),
+                Field::Host => url.host_str().unwrap_or_default().to_string(),
+                Field::Path => {
+                    let mut segments: Vec<_> = url.path_segments().into_iter().flatten().collect();
+                    segments.pop();
+                    let path = segments.join("/");
+                    percent_decode(path.as_bytes())
+                        .decode_utf8_lossy()
+                        .into_owned()
+                }
+                Field::Stem => suggested
+                    .and_then(|name| name.file_stem())
+                    .map(|stem| stem.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| format!("file_{}", context.index)),
+                Field::Ext => context
+                    .extension
+                    .map(|ext| ext.to_string())
+                    .or_else(|| {
+                        suggested
+                            .and_then(|name| name.extension())
+                            .map(|ext| ext.to_string_lossy().into_owned())
+                    })
+                    .unwrap_or_default(),
+                Field::Sha256 => {
+                    let hash = format!("{:x}", Sha256::digest(url.as_str()));
+                    hash[..width.unwrap_or(hash.len()).min(hash.len())].to_string()
+                }
+                Field::Date => today(),
+            };
+            output.push_str(&value);
+        }
+        output
+    }
+}
+
+/// Current UTC date as `YYYY-MM-DD`
+fn today() -> String {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() / 86400)
+        .unwrap_or_default() as i64;
+    // Days to civil date, from Howard Hinnant's date algorithms
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
@@ -0,0 +1,746 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use lazy_regex::{regex, regex_captures};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tracing::warn;
+
+use crate::{
+    checksum::{Checksum, PieceHashes},
+    downloader::Job,
+    metalink::parse_metalink,
+};
+
+/// A url found in a list, `line` starts at 1
+#[derive(Debug, Clone, Default)]
+pub struct ListedUrl {
+    pub url: String,
+    pub line: usize,
+    /// Digest given after the url on the same line, like `sha256=abcd...`
+    pub checksum: Option<Checksum>,
+    /// Path to save to, relative to the output folder
+    pub name: Option<String>,
+    /// Folder under the output folder to save into
+    pub dir: Option<String>,
+    pub referer: Option<String>,
+    pub headers: Vec<(String, String)>,
+    /// Other urls serving the same file
+    pub mirrors: Vec<String>,
+    /// Expected size in bytes
+    pub size: Option<u64>,
+    pub pieces: Option<PieceHashes>,
+}
+
+/// Layout of a url list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListFormat {
+    /// Free text, every http(s) url in it is downloaded
+    Text,
+    /// One JSON object per line, like `{"url": "...", "name": "a.jpg"}`
+    JsonLines,
+    /// A header row naming the columns, one of them `url`
+    Csv,
+    /// A `[[file]]` table per url
+    Toml,
+    /// An aria2c input file, a line of tab separated uris for one file followed by
+    /// indented `key=value` options like `out=`, `dir=`, `header=` and `checksum=`
+    Aria2,
+    /// A Metalink document, RFC 5854 or version 3
+    Metalink,
+}
+
+impl ListFormat {
+    /// Guess the format from the first line that is not empty or a comment.
+    ///
+    /// A line of bare uris starts both free text lists and aria2 input files, [`ListParser`]
+    /// tells them apart by the lines after it.
+    pub fn detect(line: &str) -> Self {
+        let line = line.trim();
+        if line.starts_with('{') {
+            ListFormat::JsonLines
+        } else if line.starts_with("<?xml") || line.starts_with("<metalink") {
+            ListFormat::Metalink
+        } else if line.starts_with("[[") {
+            ListFormat::Toml
+        } else if !line.contains("://")
+            && line
+                .split(',')
+                .any(|column| column.trim().trim_matches('"').eq_ignore_ascii_case("url"))
+        {
+            ListFormat::Csv
+        } else {
+            ListFormat::Text
+        }
+    }
+}
+
+/// Entry of a structured list.
+///
+/// CSV lists use the same column names, `mirrors` separated by spaces, and one
+/// `header:<name>` column per header.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Entry {
+    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dir: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    referer: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    headers: BTreeMap<String, String>,
+    /// Like `sha256=abcd...`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    checksum: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    mirrors: Vec<String>,
+    /// Expected size in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pieces: Option<PiecesEntry>,
+}
+
+/// Digests of the pieces of a file, like `{"length": 262144, "type": "sha1", "hashes": [...]}`
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PiecesEntry {
+    length: u64,
+    #[serde(rename = "type")]
+    algorithm: String,
+    hashes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TomlList {
+    #[serde(default)]
+    file: Vec<Entry>,
+}
+
+impl Entry {
+    fn into_listed(self, line: usize) -> ListedUrl {
+        let checksum = self.checksum.and_then(|checksum| {
+            checksum
+                .parse()
+                .map_err(|e| warn!("Ignore checksum on line {}: {}", line, e))
+                .ok()
+        });
+        ListedUrl {
+            url: self.url,
+            line,
+            checksum,
+            name: self.name,
+            dir: self.dir,
+            referer: self.referer,
+            headers: self.headers.into_iter().collect(),
+            mirrors: self.mirrors,
+            size: self.size,
+            pieces: self.pieces.and_then(|pieces| {
+                pieces
+                    .into_hashes()
+                    .map_err(|e| warn!("Ignore piece hashes on line {}: {}", line, e))
+                    .ok()
+            }),
+        }
+    }
+
+    /// Entry that lists `job` again with all of its options
+    pub(crate) fn from_job(job: &Job) -> Self {
+        let text = |path: &Option<PathBuf>| path.as_ref().map(|p| p.to_string_lossy().into_owned());
+        Entry {
+            url: job.url.to_string(),
+            name: text(&job.name),
+            dir: text(&job.dir),
+            referer: None,
+            headers: job
+                .headers
+                .iter()
+                .map(|(name, value)| {
+                    let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+                    (name.to_string(), value)
+                })
+                .collect(),
+            checksum: job.checksum.as_ref().map(Checksum::to_string),
+            mirrors: job.mirrors.iter().map(Url::to_string).collect(),
+            size: job.size,
+            pieces: job.pieces.as_ref().map(PiecesEntry::from_hashes),
+        }
+    }
+
+    /// Entry that lists `listed` again with all of its options
+    pub(crate) fn from_listed(listed: &ListedUrl) -> Self {
+        Entry {
+            url: listed.url.clone(),
+            name: listed.name.clone(),
+            dir: listed.dir.clone(),
+            referer: listed.referer.clone(),
+            headers: listed.headers.iter().cloned().collect(),
+            checksum: listed.checksum.as_ref().map(Checksum::to_string),
+            mirrors: listed.mirrors.clone(),
+            size: listed.size,
+            pieces: listed.pieces.as_ref().map(PiecesEntry::from_hashes),
+        }
+    }
+}
+
+impl PiecesEntry {
+    fn from_hashes(pieces: &PieceHashes) -> Self {
+        PiecesEntry {
+            length: pieces.length,
+            algorithm: pieces.algorithm.to_string(),
+            hashes: pieces.digests.iter().map(hex::encode).collect(),
+        }
+    }
+
+    fn into_hashes(self) -> Result<PieceHashes> {
+        let algorithm = self.algorithm.parse()?;
+        let digests = self
+            .hashes
+            .iter()
+            .map(|digest| Checksum::from_hex(algorithm, digest).map(|c| c.digest))
+            .collect::<Result<_>>()?;
+        if self.length == 0 {
+            return Err(anyhow!("Piece length must not be 0"));
+        }
+        Ok(PieceHashes {
+            length: self.length,
+            algorithm,
+            digests,
+        })
+    }
+}
+
+/// Reads a list line by line, in the format detected on its first meaningful line
+#[derive(Debug, Default)]
+pub struct ListParser {
+    format: Option<ListFormat>,
+    line: usize,
+    /// Lines not parsed yet, a CSV record spanning lines or a whole TOML or Metalink document
+    pending: String,
+    /// Line the pending text starts on
+    pending_line: usize,
+    csv_header: Option<csv::StringRecord>,
+    /// Whether an aria2 list may still be free text, as none of its uri lines had tabs or
+    /// options so far. Comments are kept in `pending` until it is known.
+    tentative: bool,
+    /// Aria2 entry still taking options
+    aria2: Option<ListedUrl>,
+    /// Urls of a free text list so far, as one mentioned twice is downloaded once
+    seen: HashSet<String>,
+}
+
+impl ListParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Urls completed by the next line of the list
+    pub fn push_line(&mut self, text: &str) -> Vec<ListedUrl> {
+        self.line += 1;
+        let format = match self.format {
+            Some(format) => format,
+            None if text.trim().is_empty() || text.trim_start().starts_with('#') => {
+                self.keep(text);
+                return Vec::new();
+            }
+            None => {
+                let format = match ListFormat::detect(text) {
+                    // Bare uris start aria2 input files and free text lists alike, tabs
+                    // between them or an option under them tell it is aria2
+                    ListFormat::Text if is_uri_line(text) => {
+                        self.tentative = !text.contains('\t');
+                        ListFormat::Aria2
+                    }
+                    format => format,
+                };
+                self.format = Some(format);
+                if format == ListFormat::Text {
+                    // Urls in leading comments of a free text list still count
+                    let mut urls = self.replay_text();
+                    urls.extend(self.unseen(get_line_urls(text, self.line)));
+                    return urls;
+                }
+                if format != ListFormat::Toml && !self.tentative {
+                    self.pending.clear();
+                }
+                format
+            }
+        };
+        match format {
+            ListFormat::Text => self.unseen(get_line_urls(text, self.line)),
+            ListFormat::JsonLines
+                if text.trim().is_empty() || text.trim_start().starts_with('#') =>
+            {
+                Vec::new()
+            }
+            ListFormat::JsonLines => match serde_json::from_str::<Entry>(text) {
+                Ok(entry) => vec![entry.into_listed(self.line)],
+                Err(e) => {
+                    warn!("Skip line {}: {}", self.line, e);
+                    Vec::new()
+                }
+            },
+            ListFormat::Csv => {
+                if self.pending.trim().is_empty() {
+                    self.pending.clear();
+                }
+                self.keep(text);
+                // A quoted field may hold line breaks, so wait for its closing quote
+                if self.pending.matches('"').count() % 2 == 1 || self.pending.trim().is_empty() {
+                    return Vec::new();
+                }
+                let record = std::mem::take(&mut self.pending);
+                self.parse_csv(&record).into_iter().collect()
+            }
+            ListFormat::Toml | ListFormat::Metalink => {
+                self.keep(text);
+                Vec::new()
+            }
+            ListFormat::Aria2 if text.trim().is_empty() || text.trim_start().starts_with('#') => {
+                if self.tentative {
+                    self.keep(text);
+                }
+                Vec::new()
+            }
+            ListFormat::Aria2 if is_aria2_option(text) => {
+                if self.tentative {
+                    self.tentative = false;
+                    self.pending.clear();
+                }
+                match self.aria2.as_mut() {
+                    Some(entry) => set_aria2_option(entry, text.trim(), self.line),
+                    None => warn!("Skip line {}: option without a uri", self.line),
+                }
+                Vec::new()
+            }
+            // Neither a uri line nor an option, so the list was free text all along
+            ListFormat::Aria2 if self.tentative && !is_uri_line(text) => {
+                self.tentative = false;
+                self.format = Some(ListFormat::Text);
+                let held = self.aria2.take();
+                let mut urls = self.held_as_text(held);
+                urls.extend(self.replay_text());
+                urls.sort_by_key(|listed| listed.line);
+                urls.extend(self.unseen(get_line_urls(text, self.line)));
+                urls
+            }
+            ListFormat::Aria2 if text.starts_with([' ', '\t']) => {
+                warn!("Skip line {}: expected key=value", self.line);
+                Vec::new()
+            }
+            ListFormat::Aria2 => {
+                if self.tentative && text.contains('\t') {
+                    self.tentative = false;
+                    self.pending.clear();
+                }
+                let held = self.aria2.replace(get_aria2_entry(text, self.line));
+                if self.tentative {
+                    return self.held_as_text(held);
+                }
+                held.into_iter().collect()
+            }
+        }
+    }
+
+    /// Urls of the lines kept back, once the whole list has been read
+    pub fn finish(mut self) -> Vec<ListedUrl> {
+        match self.format {
+            Some(ListFormat::Toml) => parse_toml(&self.pending),
+            Some(ListFormat::Metalink) => match parse_metalink(&self.pending) {
+                Ok(urls) => urls
+                    .into_iter()
+                    .map(|listed| ListedUrl {
+                        line: listed.line + self.pending_line - 1,
+                        ..listed
+                    })
+                    .collect(),
+                Err(e) => {
+                    warn!(
+                        "Cannot read the list as Metalink, reading it as text: {}",
+                        e
+                    );
+                    self.replay_text()
+                }
+            },
+            Some(ListFormat::Csv) if !self.pending.trim().is_empty() => {
+                let record = std::mem::take(&mut self.pending);
+                self.parse_csv(&record).into_iter().collect()
+            }
+            Some(ListFormat::Aria2) if self.tentative => {
+                let held = self.aria2.take();
+                let mut urls = self.held_as_text(held);
+                urls.extend(self.replay_text());
+                urls.sort_by_key(|listed| listed.line);
+                urls
+            }
+            Some(ListFormat::Aria2) => self.aria2.into_iter().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Urls of the lines kept before the list turned out to be free text
+    fn replay_text(&mut self) -> Vec<ListedUrl> {
+        let kept = std::mem::take(&mut self.pending);
+        let urls = (self.pending_line..)
+            .zip(kept.lines())
+            .flat_map(|(line, text)| get_line_urls(text, line))
+            .collect();
+        self.unseen(urls)
+    }
+
+    /// Urls of a uri line held while the list may still be free text, read as free text.
+    ///
+    /// Nothing confirmed aria2 for it, so it gets the same trimming as any other text line
+    /// and only its http(s) url is kept.
+    fn held_as_text(&mut self, held: Option<ListedUrl>) -> Vec<ListedUrl> {
+        let urls = held
+            .into_iter()
+            .flat_map(|held| get_line_urls(&held.url, held.line))
+            .collect();
+        self.unseen(urls)
+    }
+
+    /// Drop urls found on earlier lines
+    fn unseen(&mut self, urls: Vec<ListedUrl>) -> Vec<ListedUrl> {
+        urls.into_iter()
+            .filter(|listed| self.seen.insert(listed.url.clone()))
+            .collect()
+    }
+
+    fn keep(&mut self, text: &str) {
+        if self.pending.is_empty() {
+            self.pending_line = self.line;
+        }
+        self.pending.push_str(text);
+        self.pending.push('\n');
+    }
+
+    /// The first record is the header, later ones become urls
+    fn parse_csv(&mut self, text: &str) -> Option<ListedUrl> {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(text.as_bytes());
+        let record = match reader.records().next()? {
+            Ok(record) => record,
+            Err(e) => {
+                warn!("Skip line {}: {}", self.pending_line, e);
+                return None;
+            }
+        };
+        let Some(header) = &self.csv_header else {
+            self.csv_header = Some(record);
+            return None;
+        };
+        match get_csv_entry(header, &record) {
+            Ok(entry) => Some(entry.into_listed(self.pending_line)),
+            Err(e) => {
+                warn!("Skip line {}: {}", self.pending_line, e);
+                None
+            }
+        }
+    }
+}
+
+fn get_csv_entry(header: &csv::StringRecord, record: &csv::StringRecord) -> Result<Entry> {
+    let mut entry = Entry::default();
+    for (column, value) in header.iter().zip(record.iter()) {
+        let value = value.trim();
+        if value.is_empty() {
+            continue;
+        }
+        let column = column.trim();
+        let text = Some(value.to_string());
+        match column.to_ascii_lowercase().as_str() {
+            "url" => entry.url = value.to_string(),
+            "name" => entry.name = text,
+            "dir" => entry.dir = text,
+            "referer" => entry.referer = text,
+            "checksum" => entry.checksum = text,
+            "mirrors" => entry.mirrors = value.split_whitespace().map(String::from).collect(),
+            "size" => entry.size = Some(value.parse()?),
+            _ => match column.split_once(':') {
+                Some((prefix, name)) if prefix.eq_ignore_ascii_case("header") => {
+                    entry
+                        .headers
+                        .insert(name.trim().to_string(), value.to_string());
+                }
+                _ => return Err(anyhow!("Unknown column {}", column)),
+            },
+        }
+    }
+    if entry.url.is_empty() {
+        return Err(anyhow!("No url"));
+    }
+    Ok(entry)
+}
+
+/// Whether a line holds nothing but tab separated uris
+fn is_uri_line(text: &str) -> bool {
+    text.trim_end()
+        .split('\t')
+        .all(|uri| regex!(r"^[a-zA-Z][a-zA-Z0-9+.-]*://\S+$").is_match(uri))
+}
+
+/// Whether a line is an indented aria2 option, like `  out=a.jpg`
+fn is_aria2_option(text: &str) -> bool {
+    regex!(r"^\s+[a-z][a-z0-9-]*=").is_match(text)
+}
+
+/// The first uri is the url, later ones are mirrors of it
+fn get_aria2_entry(text: &str, line: usize) -> ListedUrl {
+    let mut uris = text
+        .split('\t')
+        .map(str::trim)
+        .filter(|uri| !uri.is_empty());
+    ListedUrl {
+        url: uris.next().unwrap_or_default().to_string(),
+        line,
+        mirrors: uris.map(String::from).collect(),
+        ..Default::default()
+    }
+}
+
+fn set_aria2_option(entry: &mut ListedUrl, option: &str, line: usize) {
+    let Some((key, value)) = option.split_once('=') else {
+        warn!("Skip line {}: expected key=value", line);
+        return;
+    };
+    let value = value.trim();
+    match key.trim() {
+        "out" => entry.name = Some(value.to_string()),
+        "dir" => entry.dir = Some(value.to_string()),
+        "referer" => entry.referer = Some(value.to_string()),
+        "user-agent" => entry
+            .headers
+            .push(("User-Agent".to_string(), value.to_string())),
+        "header" => match value.split_once(':') {
+            Some((name, value)) => entry
+                .headers
+                .push((name.trim().to_string(), value.trim().to_string())),
+            None => warn!("Ignore header on line {}: expected name: value", line),
+        },
+        // Like `sha-256=abcd...`
+        "checksum" => {
+            entry.checksum = value
+                .parse()
+                .map_err(|e| warn!("Ignore checksum on line {}: {}", line, e))
+                .ok()
+        }
+        key => warn!("Ignore option {} on line {}", key, line),
+    }
+}
+
+fn parse_toml(content: &str) -> Vec<ListedUrl> {
+    let list: TomlList = match toml::from_str(content) {
+        Ok(list) => list,
+        Err(e) => {
+            warn!("Cannot read the list: {}", e);
+            return Vec::new();
+        }
+    };
+    // Entries are numbered by the line of their `[[file]]` header
+    let lines = content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| regex!(r"^\s*\[\[\s*file\s*\]\]").is_match(line))
+        .map(|(n, _)| n + 1);
+    list.file
+        .into_iter()
+        .zip(lines)
+        .map(|(entry, line)| entry.into_listed(line))
+        .collect()
+}
+
+/// Collect every url in a list file, which may be free text or one of the [`ListFormat`]s
+pub async fn get_urls(path: &Path) -> Result<Vec<ListedUrl>> {
+    let content = fs::read_to_string(path).await?;
+    let mut parser = ListParser::new();
+    let mut urls: Vec<ListedUrl> = content
+        .lines()
+        .flat_map(|line| parser.push_line(line))
+        .collect();
+    urls.extend(parser.finish());
+    Ok(urls)
+}
+
+/// Collect the http(s) urls of line number `line` of a free text list, each one once.
+///
+/// A url ends at whitespace, a quote, an angle bracket, or a closing bracket it did not open,
+/// so `<https://a.com>`, `[text](https://a.com)` and `(see https://a.com)` all give the bare
+/// url. Punctuation ending a sentence is trimmed off, like linkifiers do.
+pub fn get_line_urls(text: &str, line: usize) -> Vec<ListedUrl> {
+    let mut urls: Vec<ListedUrl> = Vec::new();
+    let mut end = 0;
+    for scheme in regex!(r"(?i)\bhttps?://").find_iter(text) {
+        // Like the second url of `https://a.com/?next=https://b.com`
+        if scheme.start() < end {
+            continue;
+        }
+        let len = get_url_len(&text[scheme.start()..]);
+        if len <= scheme.len() {
+            continue;
+        }
+        end = scheme.start() + len;
+        let url = &text[scheme.start()..end];
+        if urls.iter().all(|listed| listed.url != url) {
+            urls.push(ListedUrl {
+                url: url.to_string(),
+                line,
+                checksum: get_checksum(&text[end..], line),
+                ..Default::default()
+            });
+        }
+    }
+    urls
+}
+
+/// Length of the url at the start of `text`
+fn get_url_len(text: &str) -> usize {
+    let mut open = Vec::new();
+    let mut len = text.len();
+    for (i, c) in text.char_indices() {
+        let opening = match c {
+            '(' | '[' | '{' => {
+                open.push(c);
+                continue;
+            }
+            ')' => '(',
+            ']' => '[',
+            '}' => '{',
+            c if c.is_whitespace() || matches!(c, '<' | '>' | '"' | '`') => {
+                len = i;
+                break;
+            }
+            _ => continue,
+        };
+        if open.pop() != Some(opening) {
+            len = i;
+            break;
+        }
+    }
+    text[..len]
+        .trim_end_matches(['.', ',', ':', ';', '!', '?', '\'', '*', '_', '~'])
+        .len()
+}
+
+/// Digest right after a url, with only whitespace between them
+fn get_checksum(text: &str, line: usize) -> Option<Checksum> {
+    let (_, checksum) = regex_captures!(
+        r"(?i)^\s+((?:md5|sha-?1|sha-?256|sha-?512)[=:][0-9a-f]+)(?:\s|$)",
+        text
+    )?;
+    checksum
+        .parse()
+        .map_err(|e| warn!("Ignore checksum on line {}: {}", line, e))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(list: &str) -> Vec<ListedUrl> {
+        let mut parser = ListParser::new();
+        let mut urls: Vec<_> = list
+            .lines()
+            .flat_map(|line| parser.push_line(line))
+            .collect();
+        urls.extend(parser.finish());
+        urls
+    }
+
+    fn urls(listed: &[ListedUrl]) -> Vec<&str> {
+        listed.iter().map(|listed| listed.url.as_str()).collect()
+    }
+
+    #[test]
+    fn line_urls_drop_trailing_punctuation() {
+        let listed = get_line_urls("see https://a.com/x.jpg, https://a.com/y.jpg.", 1);
+        assert_eq!(
+            urls(&listed),
+            ["https://a.com/x.jpg", "https://a.com/y.jpg"]
+        );
+        let listed = get_line_urls("(see https://a.com/z). and https://a.com/w_(1).png!", 1);
+        assert_eq!(
+            urls(&listed),
+            ["https://a.com/z", "https://a.com/w_(1).png"]
+        );
+        let listed = get_line_urls("<https://a.com/a> [b](https://a.com/b)", 1);
+        assert_eq!(urls(&listed), ["https://a.com/a", "https://a.com/b"]);
+    }
+
+    #[test]
+    fn line_urls_take_the_digest_after_them() {
+        let digest = "a".repeat(64);
+        let text = format!("https://a/x.jpg https://b/y.iso sha256={}", digest);
+        let listed = get_line_urls(&text, 1);
+        assert_eq!(urls(&listed), ["https://a/x.jpg", "https://b/y.iso"]);
+        assert!(listed[0].checksum.is_none());
+        assert_eq!(
+            listed[1].checksum.as_ref().map(|c| hex::encode(&c.digest)),
+            Some(digest)
+        );
+        let listed = get_line_urls("https://a/x.jpg see sha256=abcd", 1);
+        assert!(listed[0].checksum.is_none());
+    }
+
+    #[test]
+    fn line_urls_keep_http_only() {
+        assert!(get_line_urls("ftp://a.com/x.jpg mailto://a@a.com", 1).is_empty());
+    }
+
+    #[test]
+    fn plain_list_is_read_as_text() {
+        let listed = parse(
+            "https://a.com/x.jpg.\nhttps://a.com/y.jpg,\nftp://a.com/f.iso\n\
+             mailto://a@a.com\nhttps://a.com/z).\nhttps://a.com/x.jpg",
+        );
+        assert_eq!(
+            urls(&listed),
+            [
+                "https://a.com/x.jpg",
+                "https://a.com/y.jpg",
+                "https://a.com/z"
+            ]
+        );
+        assert_eq!(listed[2].line, 5);
+    }
+
+    #[test]
+    fn plain_list_turning_into_text() {
+        let listed = parse("# https://a.com/c.jpg\nhttps://a.com/x.jpg.\nsee https://a.com/y.jpg");
+        assert_eq!(
+            urls(&listed),
+            [
+                "https://a.com/c.jpg",
+                "https://a.com/x.jpg",
+                "https://a.com/y.jpg"
+            ]
+        );
+    }
+
+    #[test]
+    fn aria2_confirmed_by_option() {
+        let listed = parse("https://a.com/x.jpg.\nhttps://a.com/y\n  out=y.jpg\n  dir=d");
+        assert_eq!(urls(&listed), ["https://a.com/x.jpg", "https://a.com/y"]);
+        assert_eq!(listed[1].name.as_deref(), Some("y.jpg"));
+        assert_eq!(listed[1].dir.as_deref(), Some("d"));
+    }
+
+    #[test]
+    fn aria2_confirmed_by_tabs() {
+        let listed = parse("https://a.com/one\thttps://b.com/one\nhttps://a.com/two.iso");
+        assert_eq!(
+            urls(&listed),
+            ["https://a.com/one", "https://a.com/two.iso"]
+        );
+        assert_eq!(listed[0].mirrors, ["https://b.com/one"]);
+    }
+}